@@ -0,0 +1,172 @@
+use crate::{http, notifier::Action};
+use anyhow::Result;
+use async_trait::async_trait;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use reqwest::Url;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+    task,
+};
+use tracing::{debug, error, info, warn, Instrument};
+
+/// The `[actions]` table, notifications only get buttons when it is present.
+#[derive(Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ActionsConfig {
+    /// Address the callback endpoint listens on.
+    pub bind: SocketAddr,
+    /// How the ntfy app reaches `bind`, e.g. `https://unseenmail.example.com`.
+    pub url: String,
+    /// Secret the buttons send as a bearer token, requests without it are rejected.
+    pub token: String,
+}
+
+/// Whatever can mark a message as read, implemented by every account.
+#[async_trait]
+pub trait MarkSeen: Send + Sync {
+    async fn mark_seen(&self, folder: &str, uid: u32) -> Result<()>;
+}
+
+/// Builds the action buttons and routes their callbacks to the right account.
+pub struct Actions {
+    config: ActionsConfig,
+    accounts: Mutex<HashMap<String, Arc<dyn MarkSeen>>>,
+}
+
+impl Actions {
+    pub fn new(config: ActionsConfig) -> Self {
+        Actions {
+            config,
+            accounts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> &ActionsConfig {
+        &self.config
+    }
+
+    pub fn register(&self, account: &str, target: Arc<dyn MarkSeen>) {
+        let mut accounts = self.accounts.lock().unwrap();
+        accounts.insert(account.to_string(), target);
+    }
+
+    pub fn unregister(&self, account: &str) {
+        let mut accounts = self.accounts.lock().unwrap();
+        accounts.remove(account);
+    }
+
+    /// The "Mark as read" button for message `uid` in `folder`.
+    pub fn mark_seen_action(&self, account: &str, folder: &str, uid: u32) -> Action {
+        let encode = |s: &str| utf8_percent_encode(s, NON_ALPHANUMERIC).to_string();
+        Action {
+            label: String::from("Mark as read"),
+            url: format!(
+                "{}/seen?account={}&folder={}&uid={}",
+                self.config.url.trim_end_matches('/'),
+                encode(account),
+                encode(folder),
+                uid
+            ),
+            headers: self.headers(),
+        }
+    }
+
+    /// What a button sends along, the token, which is left out of the notification queue and
+    /// filled in again for queued notifications.
+    pub fn headers(&self) -> Vec<(String, String)> {
+        vec![(
+            String::from("Authorization"),
+            format!("Bearer {}", self.config.token),
+        )]
+    }
+
+    /// Handles `POST /seen?account=...&folder=...&uid=...` and returns the status and body.
+    async fn handle(&self, request: &http::Request) -> (&'static str, String) {
+        let expected = format!("Bearer {}", self.config.token);
+        let given = request.header("authorization").unwrap_or_default();
+        if !constant_time_eq(given.as_bytes(), expected.as_bytes()) {
+            return ("401 Unauthorized", String::from("missing or wrong token\n"));
+        }
+        let Ok(url) = Url::parse(&format!("http://localhost{}", request.target)) else {
+            return ("400 Bad Request", String::from("invalid request target\n"));
+        };
+        if request.method != "POST" || url.path() != "/seen" {
+            return ("404 Not Found", String::from("not found\n"));
+        }
+        let query: HashMap<_, _> = url.query_pairs().collect();
+        let (Some(account), Some(folder), Some(Ok(uid))) = (
+            query.get("account"),
+            query.get("folder"),
+            query.get("uid").map(|uid| uid.parse::<u32>()),
+        ) else {
+            return (
+                "400 Bad Request",
+                String::from("`account`, `folder` and `uid` are required\n"),
+            );
+        };
+        let target = self.accounts.lock().unwrap().get(account.as_ref()).cloned();
+        let Some(target) = target else {
+            return ("404 Not Found", format!("unknown account [{}]\n", account));
+        };
+        match target.mark_seen(folder, uid).await {
+            Ok(()) => {
+                info!(%account, %folder, uid, "marked as read");
+                ("200 OK", String::from("marked as read\n"))
+            }
+            Err(e) => {
+                error!(%account, %folder, uid, "failed to mark as read: {:#}", e);
+                ("502 Bad Gateway", format!("{:#}\n", e))
+            }
+        }
+    }
+}
+
+/// Compares without returning early at the first different byte, so how long a wrong token
+/// takes to be rejected says nothing about how much of it was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Answers action callbacks on `listener` until the process exits.
+pub async fn serve(listener: TcpListener, actions: Arc<Actions>) {
+    if let Ok(addr) = listener.local_addr() {
+        info!(%addr, "serving notification actions");
+    }
+    let connections = Arc::new(Semaphore::new(http::MAX_CONNECTIONS));
+    loop {
+        // never closed
+        let Ok(permit) = connections.clone().acquire_owned().await else {
+            return;
+        };
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let actions = actions.clone();
+                task::spawn(
+                    async move {
+                        if let Err(e) = respond(stream, &actions).await {
+                            debug!("action request failed: {}", e);
+                        }
+                        drop(permit);
+                    }
+                    .instrument(tracing::debug_span!("actions", %peer)),
+                );
+            }
+            Err(e) => warn!("failed to accept action connection: {}", e),
+        }
+    }
+}
+
+async fn respond(stream: TcpStream, actions: &Actions) -> std::io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let request = http::read_request(&mut stream).await?;
+    let (status, body) = actions.handle(&request).await;
+    http::write_response(&mut stream, status, "text/plain", &body).await
+}
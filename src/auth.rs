@@ -0,0 +1,180 @@
+use anyhow::{bail, Context, Result};
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use serde::Deserialize;
+use tokio::process::Command;
+
+/// How to authenticate against the IMAP server, selected with `auth.type`.
+#[derive(Deserialize, Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Auth {
+    /// Plain IMAP `LOGIN` with the account password.
+    #[default]
+    Password,
+    /// SASL `XOAUTH2`, as required by Gmail and Outlook.
+    Xoauth2 {
+        access_token: Option<String>,
+        /// Command whose stdout is the access token, run on every login so the token can be
+        /// refreshed by an external tool.
+        access_token_command: Option<String>,
+    },
+}
+
+/// The command that sends the password with `auth.type = "password"`.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMechanism {
+    /// The IMAP `LOGIN` command.
+    #[default]
+    Login,
+    /// SASL `AUTHENTICATE PLAIN`, for servers that handle it better than `LOGIN`.
+    Plain,
+    /// SASL `AUTHENTICATE CRAM-MD5`, for old servers that refuse plaintext passwords.
+    #[serde(rename = "cram-md5")]
+    CramMd5,
+}
+
+impl AuthMechanism {
+    /// The SASL name, which the server lists as `AUTH=<name>`. None for `LOGIN`, which isn't SASL.
+    pub fn sasl_name(self) -> Option<&'static str> {
+        match self {
+            AuthMechanism::Login => None,
+            AuthMechanism::Plain => Some("PLAIN"),
+            AuthMechanism::CramMd5 => Some("CRAM-MD5"),
+        }
+    }
+}
+
+impl Auth {
+    /// Resolves the XOAUTH2 access token from whichever source is configured.
+    pub async fn access_token(&self) -> Result<String> {
+        match self {
+            Auth::Xoauth2 {
+                access_token: Some(token),
+                access_token_command: None,
+            } => Ok(token.clone()),
+            Auth::Xoauth2 {
+                access_token: None,
+                access_token_command: Some(command),
+            } => run_secret_command(command).await,
+            Auth::Xoauth2 { .. } => {
+                bail!("xoauth2 needs exactly one of `access_token` or `access_token_command`")
+            }
+            Auth::Password => bail!("password authentication has no access token"),
+        }
+    }
+}
+
+/// Where the password of an account comes from, exactly one of `password`, `password_env` or
+/// `password_command` has to be configured.
+pub enum PasswordSource<'a> {
+    Literal(&'a str),
+    Env(&'a str),
+    Command(&'a str),
+}
+
+impl<'a> PasswordSource<'a> {
+    pub fn new(
+        password: Option<&'a str>,
+        password_env: Option<&'a str>,
+        password_command: Option<&'a str>,
+    ) -> Result<Self> {
+        match (password, password_env, password_command) {
+            (Some(password), None, None) => Ok(PasswordSource::Literal(password)),
+            (None, Some(var), None) => Ok(PasswordSource::Env(var)),
+            (None, None, Some(command)) => Ok(PasswordSource::Command(command)),
+            (None, None, None) => {
+                bail!("one of `password`, `password_env` or `password_command` is required")
+            }
+            _ => bail!("only one of `password`, `password_env` or `password_command` may be set"),
+        }
+    }
+
+    pub async fn resolve(&self) -> Result<String> {
+        match self {
+            PasswordSource::Literal(password) => Ok(password.to_string()),
+            PasswordSource::Env(var) => {
+                std::env::var(var).with_context(|| format!("failed to read password from ${}", var))
+            }
+            PasswordSource::Command(command) => run_secret_command(command).await,
+        }
+    }
+}
+
+/// The `XOAUTH2` initial response, sent through async-imap's `Authenticator` trait.
+///
+/// See <https://developers.google.com/gmail/imap/xoauth2-protocol>.
+pub struct XOAuth2 {
+    pub user: String,
+    pub access_token: String,
+}
+
+impl async_imap::Authenticator for &XOAuth2 {
+    type Response = String;
+
+    fn process(&mut self, _challenge: &[u8]) -> Self::Response {
+        format!(
+            "user={}\x01auth=Bearer {}\x01\x01",
+            self.user, self.access_token
+        )
+    }
+}
+
+/// The SASL `PLAIN` response, an empty authorization identity followed by the username and the
+/// password.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc4616>.
+pub struct Plain {
+    pub user: String,
+    pub password: String,
+}
+
+impl async_imap::Authenticator for &Plain {
+    type Response = String;
+
+    fn process(&mut self, _challenge: &[u8]) -> Self::Response {
+        format!("\0{}\0{}", self.user, self.password)
+    }
+}
+
+/// The SASL `CRAM-MD5` response, the username and the hex HMAC-MD5 of the server's challenge
+/// keyed with the password.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc2195>.
+pub struct CramMd5 {
+    pub user: String,
+    pub password: String,
+}
+
+impl async_imap::Authenticator for &CramMd5 {
+    type Response = String;
+
+    fn process(&mut self, challenge: &[u8]) -> Self::Response {
+        let mut mac = Hmac::<Md5>::new_from_slice(self.password.as_bytes())
+            .expect("HMAC takes keys of any length");
+        mac.update(challenge);
+        let digest: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        format!("{} {}", self.user, digest)
+    }
+}
+
+/// Runs `command` through `sh -c` and returns its stdout without the trailing newline.
+pub async fn run_secret_command(command: &str) -> Result<String> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .await
+        .with_context(|| format!("failed to run `{}`", command))?;
+    if !output.status.success() {
+        bail!("`{}` exited with {}", command, output.status);
+    }
+    let stdout = String::from_utf8(output.stdout)
+        .with_context(|| format!("`{}` printed invalid UTF-8", command))?;
+    Ok(stdout.trim_end_matches(['\r', '\n']).to_string())
+}
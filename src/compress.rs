@@ -0,0 +1,201 @@
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use std::{
+    fmt, io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Turns on compression for a `Compressible` stream, from outside the IMAP session that owns
+/// it.
+#[derive(Clone, Default)]
+pub struct CompressSwitch(Arc<AtomicBool>);
+
+impl CompressSwitch {
+    /// Everything read and written from now on is deflated. Call this right after the tagged OK
+    /// to `COMPRESS DEFLATE` and before the next command.
+    pub fn enable(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    fn enabled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// A stream that starts out plain and speaks raw DEFLATE (RFC 1951) in both directions once its
+/// `CompressSwitch` is enabled, as IMAP COMPRESS=DEFLATE (RFC 4978) has it.
+///
+/// Every flush ends the compressed data written so far with a sync flush, so a command is never
+/// stuck in the compressor while we wait for its response.
+pub struct Compressible<S> {
+    inner: S,
+    switch: CompressSwitch,
+    deflate: Option<Deflate>,
+}
+
+struct Deflate {
+    compress: Compress,
+    decompress: Decompress,
+    /// Compressed bytes read from the stream that haven't been inflated yet.
+    input: Vec<u8>,
+    /// Compressed bytes that haven't been written to the stream yet.
+    output: Vec<u8>,
+    /// Whether data was compressed since the last sync flush.
+    unflushed: bool,
+}
+
+impl<S> Compressible<S> {
+    pub fn new(inner: S, switch: CompressSwitch) -> Self {
+        Compressible {
+            inner,
+            switch,
+            deflate: None,
+        }
+    }
+
+    fn deflate(&mut self) -> Option<&mut Deflate> {
+        if self.deflate.is_none() && self.switch.enabled() {
+            self.deflate = Some(Deflate {
+                compress: Compress::new(Compression::default(), false),
+                decompress: Decompress::new(false),
+                input: Vec::new(),
+                output: Vec::new(),
+                unflushed: false,
+            });
+        }
+        self.deflate.as_mut()
+    }
+}
+
+impl<S> fmt::Debug for Compressible<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Compressible")
+            .field("compressed", &self.deflate.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Writes out everything in `output`.
+fn poll_write_output<S: AsyncWrite + Unpin>(
+    inner: &mut S,
+    output: &mut Vec<u8>,
+    cx: &mut Context,
+) -> Poll<io::Result<()>> {
+    while !output.is_empty() {
+        let written = ready!(Pin::new(&mut *inner).poll_write(cx, output))?;
+        if written == 0 {
+            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+        }
+        output.drain(..written);
+    }
+    Poll::Ready(Ok(()))
+}
+
+fn compress_error(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Compressible<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.deflate().is_none() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let deflate = this.deflate.as_mut().unwrap();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            // even without new input the decompressor may still hold output
+            let (before_in, before_out) = (
+                deflate.decompress.total_in(),
+                deflate.decompress.total_out(),
+            );
+            let status = deflate
+                .decompress
+                .decompress(
+                    &deflate.input,
+                    buf.initialize_unfilled(),
+                    FlushDecompress::Sync,
+                )
+                .map_err(compress_error)?;
+            let consumed = (deflate.decompress.total_in() - before_in) as usize;
+            let produced = (deflate.decompress.total_out() - before_out) as usize;
+            deflate.input.drain(..consumed);
+            buf.advance(produced);
+            if produced > 0 || status == Status::StreamEnd {
+                return Poll::Ready(Ok(()));
+            }
+            if consumed > 0 {
+                continue;
+            }
+            let mut chunk = [0; 4096];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                // end of the stream
+                return Poll::Ready(Ok(()));
+            }
+            deflate.input.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Compressible<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, data: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.deflate().is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, data);
+        }
+        let deflate = this.deflate.as_mut().unwrap();
+        // keep at most one write's worth of compressed data around
+        ready!(poll_write_output(&mut this.inner, &mut deflate.output, cx))?;
+        let before = deflate.compress.total_in();
+        while ((deflate.compress.total_in() - before) as usize) < data.len() {
+            let consumed = (deflate.compress.total_in() - before) as usize;
+            deflate.output.reserve(data.len() - consumed + 64);
+            deflate
+                .compress
+                .compress_vec(&data[consumed..], &mut deflate.output, FlushCompress::None)
+                .map_err(compress_error)?;
+        }
+        deflate.unflushed = true;
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.deflate().is_some() {
+            let deflate = this.deflate.as_mut().unwrap();
+            if std::mem::take(&mut deflate.unflushed) {
+                // a sync flush is done once it leaves room in the output
+                loop {
+                    deflate.output.reserve(64);
+                    deflate
+                        .compress
+                        .compress_vec(&[], &mut deflate.output, FlushCompress::Sync)
+                        .map_err(compress_error)?;
+                    if deflate.output.len() < deflate.output.capacity() {
+                        break;
+                    }
+                }
+            }
+            ready!(poll_write_output(&mut this.inner, &mut deflate.output, cx))?;
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
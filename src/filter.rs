@@ -0,0 +1,198 @@
+use anyhow::{bail, Result};
+use mailparse::{MailAddr, MailHeader, MailHeaderMap};
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+
+/// Per-account include/exclude rules deciding which new messages are worth a notification.
+///
+/// An exclude match always wins. If any include rules are present, a message also has to match at
+/// least one of them. Without any rules every message is notified, as before. Include rules and
+/// the `tag` rules, which don't filter anything, can add ntfy tags to the mail they match.
+#[derive(Deserialize, Default)]
+#[serde(try_from = "RawFilters")]
+pub struct Filters {
+    include: Vec<Rule>,
+    exclude: Vec<Rule>,
+    tag: Vec<Rule>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawFilters {
+    /// Matching is case-insensitive unless this is set, rules can override it individually.
+    #[serde(default)]
+    case_sensitive: bool,
+    #[serde(default)]
+    include: Vec<RawRule>,
+    #[serde(default)]
+    exclude: Vec<RawRule>,
+    #[serde(default)]
+    tag: Vec<RawRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRule {
+    header: Header,
+    glob: Option<String>,
+    regex: Option<String>,
+    case_sensitive: Option<bool>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Header {
+    From,
+    Subject,
+    To,
+}
+
+impl Header {
+    fn name(self) -> &'static str {
+        match self {
+            Header::From => "From",
+            Header::Subject => "Subject",
+            Header::To => "To",
+        }
+    }
+}
+
+struct Rule {
+    header: Header,
+    pattern: Regex,
+    tags: Vec<String>,
+}
+
+impl TryFrom<RawFilters> for Filters {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawFilters) -> Result<Self> {
+        let compile = |rules: Vec<RawRule>| {
+            rules
+                .into_iter()
+                .map(|rule| Rule::new(rule, raw.case_sensitive))
+                .collect::<Result<Vec<_>>>()
+        };
+        if raw.exclude.iter().any(|rule| !rule.tags.is_empty()) {
+            bail!("excluded mail isn't notified, so `tags` on an exclude rule would never show");
+        }
+        if raw.tag.iter().any(|rule| rule.tags.is_empty()) {
+            bail!("a `tag` rule needs `tags`");
+        }
+        Ok(Filters {
+            include: compile(raw.include)?,
+            exclude: compile(raw.exclude)?,
+            tag: compile(raw.tag)?,
+        })
+    }
+}
+
+impl Rule {
+    fn new(raw: RawRule, case_sensitive: bool) -> Result<Self> {
+        let pattern = match (raw.glob, raw.regex) {
+            (Some(glob), None) => glob_to_regex(&glob),
+            (None, Some(regex)) => regex,
+            _ => bail!("a filter rule needs exactly one of `glob` or `regex`"),
+        };
+        let pattern = RegexBuilder::new(&pattern)
+            .case_insensitive(!raw.case_sensitive.unwrap_or(case_sensitive))
+            .build()?;
+        Ok(Rule {
+            header: raw.header,
+            pattern,
+            tags: raw.tags,
+        })
+    }
+
+    /// Address headers match on the whole decoded value as well as on every bare address, so
+    /// `*@example.com` works without having to account for display names and angle brackets.
+    fn matches(&self, headers: &[MailHeader]) -> bool {
+        headers
+            .get_all_headers(self.header.name())
+            .into_iter()
+            .any(|header| {
+                if self.pattern.is_match(&header.get_value()) {
+                    return true;
+                }
+                match self.header {
+                    Header::Subject => false,
+                    Header::From | Header::To => mailparse::addrparse_header(header)
+                        .map(|addrs| addrs.iter().any(|addr| self.matches_addr(addr)))
+                        .unwrap_or(false),
+                }
+            })
+    }
+
+    fn matches_addr(&self, addr: &MailAddr) -> bool {
+        match addr {
+            MailAddr::Single(info) => self.pattern.is_match(&info.addr),
+            MailAddr::Group(group) => group
+                .addrs
+                .iter()
+                .any(|info| self.pattern.is_match(&info.addr)),
+        }
+    }
+}
+
+impl Filters {
+    pub fn allows(&self, headers: &[MailHeader]) -> bool {
+        if self.exclude.iter().any(|rule| rule.matches(headers)) {
+            return false;
+        }
+        self.include.is_empty() || self.include.iter().any(|rule| rule.matches(headers))
+    }
+
+    /// The tags of every include and `tag` rule that matches, in the order of the rules.
+    pub fn tags(&self, headers: &[MailHeader]) -> Vec<String> {
+        self.include
+            .iter()
+            .chain(&self.tag)
+            .filter(|rule| !rule.tags.is_empty() && rule.matches(headers))
+            .flat_map(|rule| rule.tags.iter().cloned())
+            .collect()
+    }
+}
+
+/// Whether a bare address in the `From` header is one of `vip_senders`. An entry with a local
+/// part, like `boss@example.com`, has to match the whole address, any other entry, like
+/// `example.com` or `@example.com`, is a domain. Both ignore case.
+pub fn from_vip(vip_senders: &[String], headers: &[MailHeader]) -> bool {
+    let is_vip = |addr: &str| {
+        let domain = addr.rsplit_once('@').map_or("", |(_, domain)| domain);
+        vip_senders.iter().any(|entry| {
+            let entry_domain = entry.strip_prefix('@').unwrap_or(entry);
+            if entry_domain.contains('@') {
+                entry.eq_ignore_ascii_case(addr)
+            } else {
+                entry_domain.eq_ignore_ascii_case(domain)
+            }
+        })
+    };
+    headers
+        .get_all_headers("From")
+        .into_iter()
+        .filter_map(|header| mailparse::addrparse_header(header).ok())
+        .any(|addrs| {
+            addrs.iter().any(|addr| match addr {
+                MailAddr::Single(info) => is_vip(&info.addr),
+                MailAddr::Group(group) => group.addrs.iter().any(|info| is_vip(&info.addr)),
+            })
+        })
+}
+
+/// Translates a glob where `*` matches any run of characters and `?` a single one into an
+/// anchored regex.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push('$');
+    regex
+}
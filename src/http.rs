@@ -0,0 +1,102 @@
+//! Just enough HTTP/1.1 for the metrics and action endpoints: one request per connection, no
+//! request bodies.
+
+use std::{io, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::timeout,
+};
+
+/// The longest request line or header line we read, everything we serve fits easily.
+const MAX_LINE: u64 = 8 * 1024;
+/// The most headers we read before giving up on a request.
+const MAX_HEADERS: usize = 64;
+/// How long a client gets to send its request, and again to take the response, so idle
+/// connections don't pile up.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// The most connections answered at once, further clients wait to be accepted.
+pub const MAX_CONNECTIONS: usize = 16;
+
+pub struct Request {
+    pub method: String,
+    /// Path and query, e.g. `/seen?uid=1`.
+    pub target: String,
+    /// Header names are lowercased.
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub async fn read_request(stream: &mut BufReader<TcpStream>) -> io::Result<Request> {
+    timeout(TIMEOUT, read(stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no request in time"))?
+}
+
+async fn read(stream: &mut BufReader<TcpStream>) -> io::Result<Request> {
+    let request_line = read_line(stream).await?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default().to_string();
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(stream).await?;
+        if line.trim().is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "too many headers",
+            ));
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+    Ok(Request {
+        method,
+        target,
+        headers,
+    })
+}
+
+/// Reads one line of at most `MAX_LINE` bytes, an empty string at the end of the stream.
+async fn read_line(stream: &mut BufReader<TcpStream>) -> io::Result<String> {
+    let mut line = String::new();
+    let read = (&mut *stream).take(MAX_LINE).read_line(&mut line).await?;
+    if read as u64 == MAX_LINE && !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+    }
+    Ok(line)
+}
+
+pub async fn write_response(
+    stream: &mut BufReader<TcpStream>,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    let write = async {
+        stream.get_mut().write_all(response.as_bytes()).await?;
+        stream.get_mut().shutdown().await
+    };
+    timeout(TIMEOUT, write)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "response not taken in time"))?
+}
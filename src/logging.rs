@@ -0,0 +1,243 @@
+use crate::config::{Account, Config};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::fs::OpenOptions;
+use tracing::{
+    field::{Field, Visit},
+    Event, Subscriber,
+};
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+use tracing_subscriber::{
+    field::MakeExt,
+    filter::{Directive, LevelFilter},
+    fmt::{
+        self,
+        format::{self, FormatEvent, FormatFields, JsonFields},
+        time::{FormatTime, SystemTime},
+        FmtContext, FormattedFields,
+    },
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Registry,
+};
+
+/// How log lines are written, `--log-format`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per event, for log collectors.
+    Json,
+}
+
+/// The installed subscriber, kept so a reload can change the account log levels.
+pub struct Logging {
+    filter: reload::Handle<EnvFilter, Registry>,
+    /// Flushes the log file when dropped, so it has to live as long as the process.
+    _guard: Option<WorkerGuard>,
+}
+
+impl Logging {
+    /// Logs to stdout and, with `log_file` set, appends the same events to that file.
+    pub fn init(config: &Config, format: LogFormat) -> Result<Logging> {
+        let (filter, handle) = reload::Layer::new(env_filter(&config.accounts));
+        let (writer, guard) = match &config.log_file {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("failed to open log file {}", path.display()))?;
+                let (writer, guard) = tracing_appender::non_blocking(file);
+                (Some(writer), Some(guard))
+            }
+            None => (None, None),
+        };
+        let json = format == LogFormat::Json;
+        tracing_subscriber::registry()
+            .with(filter)
+            .with((!json).then(fmt::layer))
+            .with(writer.clone().filter(|_| !json).map(text_file_layer))
+            .with(json.then(|| json_layer(std::io::stdout)))
+            .with(writer.filter(|_| json).map(json_layer))
+            .init();
+        Ok(Logging {
+            filter: handle,
+            _guard: guard,
+        })
+    }
+
+    /// Applies the `log_level` of reloaded accounts.
+    pub fn reload(&self, accounts: &[Account]) {
+        if let Err(e) = self.filter.reload(env_filter(accounts)) {
+            tracing::error!("failed to update log levels: {}", e);
+        }
+    }
+}
+
+fn text_file_layer<S>(writer: NonBlocking) -> impl tracing_subscriber::Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    // span fields are formatted once per field formatter type and shared between layers, so the
+    // file needs its own type or it gets stdout's colored fields
+    let fields = fmt::format::debug_fn(|writer, field, value| {
+        if field.name() == "message" {
+            write!(writer, "{:?}", value)
+        } else {
+            write!(writer, "{}={:?}", field, value)
+        }
+    })
+    .delimited(" ");
+    fmt::layer()
+        .with_ansi(false)
+        .fmt_fields(fields)
+        .with_writer(writer)
+}
+
+/// Writes every event to `writer` as one line of JSON, see `JsonFormat`.
+pub fn json_layer<S, W>(writer: W) -> impl tracing_subscriber::Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    fmt::layer()
+        .event_format(JsonFormat)
+        .fmt_fields(JsonFields::new())
+        .with_writer(writer)
+}
+
+/// Writes an event as one line of JSON with `timestamp`, `level`, `target`, `message` and, for
+/// the events of an account, `account`, followed by any other fields of the event.
+///
+/// The JSON formatter of tracing-subscriber would nest the account under a list of spans, where
+/// it also clashes with the span's own `name`.
+struct JsonFormat;
+
+#[derive(Serialize)]
+struct JsonEvent<'a> {
+    timestamp: String,
+    level: &'a str,
+    target: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    account: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<Value>,
+    #[serde(flatten)]
+    fields: Map<String, Value>,
+}
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: format::Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut format::Writer::new(&mut timestamp))?;
+        let mut visitor = JsonVisitor::default();
+        event.record(&mut visitor);
+        // the span fields are stored already formatted, as JSON thanks to `JsonFields`
+        let account = ctx
+            .event_scope()
+            .into_iter()
+            .flatten()
+            .find(|span| span.name() == "account")
+            .and_then(|span| {
+                let extensions = span.extensions();
+                let fields = extensions.get::<FormattedFields<N>>()?;
+                let mut fields: Map<String, Value> = serde_json::from_str(fields).ok()?;
+                fields.remove("name")
+            });
+        let metadata = event.metadata();
+        let line = JsonEvent {
+            timestamp,
+            level: metadata.level().as_str(),
+            target: metadata.target(),
+            account,
+            message: visitor.0.remove("message"),
+            fields: visitor.0,
+        };
+        let line = serde_json::to_string(&line).map_err(|_| std::fmt::Error)?;
+        writeln!(writer, "{}", line)
+    }
+}
+
+#[derive(Default)]
+struct JsonVisitor(Map<String, Value>);
+
+impl Visit for JsonVisitor {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value).into());
+    }
+}
+
+/// `RUST_LOG`, or `info` without it, plus a directive for every account with a `log_level`.
+///
+/// The account directives match the `account` span every account task runs in. They can only
+/// make an account more verbose, events the global filter lets through are never dropped.
+fn env_filter(accounts: &[Account]) -> EnvFilter {
+    let mut filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy();
+    for account in accounts {
+        let Some(level) = account.log_level else {
+            continue;
+        };
+        let directive = format!(
+            "[account{{name={}}}]={}",
+            name_pattern(&account.name),
+            level
+        );
+        match directive.parse::<Directive>() {
+            Ok(directive) => filter = filter.add_directive(directive),
+            Err(e) => eprintln!("ignoring `log_level` of [{}]: {}", account.name, e),
+        }
+    }
+    filter
+}
+
+/// A regex matching exactly `name`, safe to put in a directive.
+///
+/// Directives are split on `,` and `}` before the value is read, and values that look like a
+/// number or a boolean are not treated as a regex at all, so every ASCII character other than a
+/// letter or digit is written as a `\xHH` escape and the whole name is wrapped in a group.
+fn name_pattern(name: &str) -> String {
+    let escaped: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_alphanumeric() {
+                format!("\\x{:02x}", c as u32)
+            } else {
+                c.to_string()
+            }
+        })
+        .collect();
+    format!("(?:{})", escaped)
+}
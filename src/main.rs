@@ -1,228 +1,2588 @@
-use anyhow::Result;
-use async_imap::{extensions::idle::IdleResponse, Session};
-use async_native_tls::TlsStream;
+mod actions;
+mod auth;
+mod compress;
+mod config;
+mod filter;
+mod http;
+mod logging;
+mod metrics;
+mod notifier;
+mod proxy;
+mod queue;
+mod rate_limit;
+mod startup;
+mod state;
+mod systemd;
+mod template;
+#[cfg(test)]
+mod tests;
+
+use actions::{Actions, MarkSeen};
+use anyhow::{bail, Context, Result};
+use async_imap::{
+    extensions::idle::IdleResponse,
+    imap_proto::{BodyStructure, Capability, NameAttribute, Response},
+    types::{Flag, Name, UnsolicitedResponse},
+    Session,
+};
+use async_trait::async_trait;
+use auth::{Auth, AuthMechanism, CramMd5, Plain, XOAuth2};
 use clap::Parser;
-use futures::{future::join_all, StreamExt};
-use ntfy::{Dispatcher, Payload, Priority, Url};
-use serde::Deserialize;
-use std::{fs, io::Read, path::PathBuf, time::Duration};
-use tokio::{net::TcpStream, task, time::sleep};
+use compress::{CompressSwitch, Compressible};
+use config::{Account, AddressFamily, Config, DisplayTimezone, Encryption};
+use futures::{future::join_all, FutureExt, StreamExt};
+use logging::{LogFormat, Logging};
+use mailparse::{DispositionType, MailAddr, MailHeader, MailHeaderMap, ParsedMail, SingleInfo};
+use metrics::Metrics;
+use notifier::{MailDetails, Notification, Notifier, Priority};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use queue::NotificationQueue;
+use rate_limit::TokenBucket;
+use socket2::{SockRef, TcpKeepalive};
+use state::{MailboxState, StateStore};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Debug},
+    net::{IpAddr, SocketAddr},
+    panic::AssertUnwindSafe,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use systemd::{Liveness, SdNotify};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{lookup_host, TcpListener, TcpSocket, TcpStream},
+    signal::unix::{signal, SignalKind},
+    sync::{oneshot, Notify, Semaphore},
+    task,
+    time::{sleep, Instant},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    /// Config file, or `-` for stdin, can be given several times to combine the accounts of every
+    /// file. Without it the config is read from the UNSEENMAIL_CONFIG environment variable.
     #[arg(short, long)]
-    config: PathBuf,
+    config: Vec<PathBuf>,
+    /// Directory whose `*.toml` files are read after every `--config`, e.g. one file per account.
+    #[arg(long, value_name = "DIR")]
+    config_dir: Option<PathBuf>,
+    /// Check every account once and exit instead of waiting for new mail.
+    #[arg(long)]
+    once: bool,
+    /// Log the notifications that would be sent instead of sending them.
+    #[arg(long)]
+    dry_run: bool,
+    /// Check the config and the password sources without connecting anywhere.
+    #[arg(long)]
+    validate: bool,
+    /// Send a test notification for every account and exit, without connecting to IMAP.
+    #[arg(long)]
+    test_notify: bool,
+    /// Print the folders of the account with this name and exit.
+    #[arg(long, value_name = "ACCOUNT")]
+    list_folders: Option<String>,
+    /// Only run the account with this name, can be given several times.
+    #[arg(long = "account", value_name = "NAME")]
+    accounts: Vec<String>,
+    /// How to write logs, `json` for one JSON object per event.
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// Print an example config with every key and exit.
+    #[arg(long)]
+    print_config_template: bool,
+}
+
+/// Returns the first `key` header with RFC 2047 encoded-words decoded and folded lines joined.
+///
+/// `MailHeader::get_value` decodes each encoded-word with its own charset, so mixed-charset values
+/// work, and leaves words it cannot decode (unknown charset, bad base64) as their raw text. Tabs and
+/// repeated spaces left over from unfolding are collapsed so the notification reads as one line.
+fn decoded_header(headers: &[MailHeader], key: &str) -> Option<String> {
+    let value = headers.get_first_header(key)?.get_value();
+    Some(value.split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+/// The `Date` header converted to `timezone`, or as written when it can't be parsed.
+fn display_date(date: &str, timezone: DisplayTimezone) -> String {
+    // mailparse's own parser reads anything, garbage included, as some date; a trailing comment
+    // like `(UTC)` is common but not something chrono accepts
+    let without_comment = match date.trim_end().strip_suffix(')') {
+        Some(rest) => rest.rsplit_once('(').map_or(date, |(rest, _)| rest),
+        None => date,
+    };
+    chrono::DateTime::parse_from_rfc2822(without_comment.trim())
+        .ok()
+        .and_then(|parsed| timezone.format(parsed.timestamp()))
+        .unwrap_or_else(|| date.to_string())
+}
+
+/// Formats the `From` header as `Display Name <addr>`, with any encoded-words in the display name
+/// already decoded by mailparse.
+fn sender(headers: &[MailHeader]) -> String {
+    let Some(header) = headers.get_first_header("From") else {
+        return String::from("<unknown sender>");
+    };
+    let addrs = match mailparse::addrparse_header(header) {
+        Ok(addrs) => addrs,
+        Err(_) => return decoded_header(headers, "From").unwrap_or_default(),
+    };
+    match addrs.first() {
+        Some(MailAddr::Single(info)) => display_addr(info),
+        Some(MailAddr::Group(group)) => group.group_name.clone(),
+        None => String::from("<unknown sender>"),
+    }
+}
+
+fn display_addr(info: &SingleInfo) -> String {
+    match &info.display_name {
+        Some(name) => format!("{} <{}>", name, info.addr),
+        None => info.addr.clone(),
+    }
+}
+
+/// Only this many recipients are listed, the rest are counted.
+const MAX_RECIPIENTS: usize = 3;
+
+/// Who the mail was sent to, for `recipient_in_notification`: the first `Delivered-To` address,
+/// which is the alias that got the mail into this mailbox, then the other `To` and `Cc`
+/// recipients. `None` without any of these headers.
+fn recipients(headers: &[MailHeader]) -> Option<String> {
+    let delivered_to = headers
+        .get_first_header("Delivered-To")
+        .and_then(|header| mailparse::addrparse_header(header).ok())
+        .and_then(|addrs| match addrs.first() {
+            Some(MailAddr::Single(info)) => Some(info.addr.clone()),
+            _ => None,
+        });
+    let mut recipients = Vec::new();
+    for header in headers
+        .get_all_headers("To")
+        .into_iter()
+        .chain(headers.get_all_headers("Cc"))
+    {
+        let Ok(addrs) = mailparse::addrparse_header(header) else {
+            continue;
+        };
+        for addr in addrs.iter() {
+            match addr {
+                MailAddr::Single(info) => recipients.push(info.clone()),
+                MailAddr::Group(group) => recipients.extend(group.addrs.iter().cloned()),
+            }
+        }
+    }
+    let mut shown = Vec::new();
+    if let Some(delivered_to) = &delivered_to {
+        // shown with the display name it has in To or Cc, if it is there too
+        match recipients
+            .iter()
+            .position(|info| info.addr.eq_ignore_ascii_case(delivered_to))
+        {
+            Some(i) => shown.push(display_addr(&recipients.remove(i))),
+            None => shown.push(delivered_to.clone()),
+        }
+    }
+    shown.extend(recipients.iter().map(display_addr));
+    if shown.is_empty() {
+        return None;
+    }
+    let more = shown.len().saturating_sub(MAX_RECIPIENTS);
+    shown.truncate(MAX_RECIPIENTS);
+    let mut recipients = shown.join(", ");
+    if more > 0 {
+        recipients.push_str(&format!(" and {} more", more));
+    }
+    Some(recipients)
+}
+
+/// The preview is cut at this many characters.
+const PREVIEW_LEN: usize = 200;
+
+/// The message header plus the start of the body, which is plenty for a preview without
+/// downloading large attachments.
+///
+/// Every section is fetched with `BODY.PEEK`. A plain `BODY[...]` fetch sets `\Seen` (RFC 3501,
+/// 6.4.5), and some servers treat `RFC822.HEADER` the same way, so checking for new mail would
+/// mark it as read. The response still comes back as `BODY[HEADER]`, which `Fetch::header`
+/// returns just like `RFC822.HEADER`.
+const FETCH_ITEMS: &str = "FLAGS BODY.PEEK[HEADER] BODY.PEEK[TEXT]<0.16384>";
+
+/// Only the headers the notification, the filters and the priority changes look at, for
+/// `minimal_fetch`. Without the body there is no preview.
+const MINIMAL_FETCH_ITEMS: &str = concat!(
+    "FLAGS BODY.PEEK[HEADER.FIELDS (SUBJECT FROM TO CC DELIVERED-TO DATE MESSAGE-ID X-PRIORITY ",
+    "IMPORTANCE LIST-ID LIST-UNSUBSCRIBE)]"
+);
+
+/// How long to wait before the second attempt at a notification, doubled for every one after.
+const NOTIFY_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How long logging out may take before the connection is dropped anyway.
+const LOGOUT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Returns the start of the first text/plain part of the message, collapsed onto one line.
+///
+/// `header` and `text` are joined back into a message so mailparse can walk multipart bodies and
+/// undo the transfer encoding. Messages without a usable text/plain part have no preview.
+fn body_preview(header: &[u8], text: &[u8]) -> Option<String> {
+    let raw = [header, text].concat();
+    let mail = mailparse::parse_mail(&raw).ok()?;
+    let part = find_text_plain(&mail)?;
+    let body = decoded_body(part)?;
+    let body = body.split_whitespace().collect::<Vec<_>>().join(" ");
+    if body.is_empty() {
+        return None;
+    }
+    match body.char_indices().nth(PREVIEW_LEN) {
+        Some((end, _)) => Some(format!("{}…", &body[..end])),
+        None => Some(body),
+    }
+}
+
+/// The body of `part` decoded with its declared charset, e.g. ISO-8859-1 or GB2312.
+///
+/// mailparse would read a part without a charset, or with one it doesn't know, as US-ASCII or
+/// Latin-1, which turns the undeclared UTF-8 many mailers send into mojibake. Those parts are read
+/// as UTF-8 instead, with invalid bytes replaced. US-ASCII is a subset of UTF-8, so a declared
+/// `us-ascii` is treated the same way in case the mailer lied.
+fn decoded_body(part: &ParsedMail) -> Option<String> {
+    let body = part.get_body_raw().ok()?;
+    let encoding = part
+        .ctype
+        .params
+        .get("charset")
+        .filter(|charset| !charset.eq_ignore_ascii_case("us-ascii"))
+        .and_then(|charset| encoding_rs::Encoding::for_label(charset.trim().as_bytes()));
+    Some(match encoding {
+        Some(encoding) => encoding.decode(&body).0.into_owned(),
+        None => String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+/// Replaces every `{name}` in `template` with `value(name)`, leaving names without a value as they
+/// are. Values are not scanned again, so a subject containing `{from}` is kept literally.
+fn fill_placeholders(template: &str, value: impl Fn(&str) -> Option<String>) -> String {
+    let mut filled = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match rest
+            .find('}')
+            .and_then(|end| Some((value(&rest[1..end])?, end)))
+        {
+            Some((value, end)) => {
+                filled.push_str(&value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+/// The size of every attachment of the message, i.e. part marked `attachment`, or not marked at
+/// all but carrying a file name.
+///
+/// Sizes are as the server stores them, so a base64 encoded attachment counts about a third more
+/// than the file. A multipart marked as an attachment counts as 0, its parts are listed anyway.
+fn attachment_sizes(structure: &BodyStructure) -> Vec<u32> {
+    let (common, size, parts) = match structure {
+        BodyStructure::Basic { common, other, .. } | BodyStructure::Text { common, other, .. } => {
+            (common, other.octets, &[][..])
+        }
+        BodyStructure::Message {
+            common,
+            other,
+            body,
+            ..
+        } => (common, other.octets, std::slice::from_ref(&**body)),
+        BodyStructure::Multipart { common, bodies, .. } => (common, 0, &bodies[..]),
+    };
+    let is_attachment = match &common.disposition {
+        Some(disposition) => disposition.ty.eq_ignore_ascii_case("attachment"),
+        None => {
+            !matches!(structure, BodyStructure::Multipart { .. })
+                && common
+                    .ty
+                    .params
+                    .iter()
+                    .flatten()
+                    .any(|(key, _)| key.eq_ignore_ascii_case("name"))
+        }
+    };
+    let mut sizes: Vec<u32> = parts.iter().flat_map(attachment_sizes).collect();
+    if is_attachment {
+        sizes.push(size);
+    }
+    sizes
+}
+
+/// Formats the unread count for a notification title, e.g. ` (5 unread)`.
+fn unread_suffix(unread: Option<u32>) -> String {
+    unread.map_or_else(String::new, |unread| format!(" ({} unread)", unread))
+}
+
+/// The SASL mechanisms a server offers before logging in, from the `AUTH=` capabilities.
+///
+/// Only a `Session` can ask for capabilities, so the `CAPABILITY` response is picked out of the
+/// untagged responses.
+async fn auth_mechanisms(
+    client: &mut async_imap::Client<Box<dyn ImapStream>>,
+) -> Result<HashSet<String>> {
+    let (sender, receiver) = async_channel::bounded(16);
+    client
+        .run_command_and_check_ok("CAPABILITY", Some(sender))
+        .await?;
+    let mut mechanisms = HashSet::new();
+    while let Ok(response) = receiver.try_recv() {
+        if let UnsolicitedResponse::Other(data) = response {
+            if let Response::Capabilities(capabilities) = data.parsed() {
+                mechanisms.extend(
+                    capabilities
+                        .iter()
+                        .filter_map(|capability| match capability {
+                            Capability::Auth(mechanism) => Some(mechanism.to_uppercase()),
+                            _ => None,
+                        }),
+                );
+            }
+        }
+    }
+    Ok(mechanisms)
+}
+
+/// Lists folders with their children indented below them, as `--list-folders` prints them.
+///
+/// The depth comes from the server's hierarchy delimiter, whatever it is, and the full name is
+/// printed so it can be copied into `folders` as is.
+fn folder_tree(names: &[Name]) -> Vec<String> {
+    let mut names: Vec<_> = names.iter().collect();
+    names.sort_by(|a, b| a.name().cmp(b.name()));
+    names
+        .into_iter()
+        .map(|name| {
+            let depth = name
+                .delimiter()
+                .filter(|delimiter| !delimiter.is_empty())
+                .map_or(0, |delimiter| name.name().matches(delimiter).count());
+            let attributes: Vec<_> = name
+                .attributes()
+                .iter()
+                .map(|attribute| match attribute {
+                    NameAttribute::NoInferiors => "\\Noinferiors",
+                    NameAttribute::NoSelect => "\\Noselect",
+                    NameAttribute::Marked => "\\Marked",
+                    NameAttribute::Unmarked => "\\Unmarked",
+                    NameAttribute::All => "\\All",
+                    NameAttribute::Archive => "\\Archive",
+                    NameAttribute::Drafts => "\\Drafts",
+                    NameAttribute::Flagged => "\\Flagged",
+                    NameAttribute::Junk => "\\Junk",
+                    NameAttribute::Sent => "\\Sent",
+                    NameAttribute::Trash => "\\Trash",
+                    NameAttribute::Extension(extension) => extension,
+                    _ => "",
+                })
+                .filter(|attribute| !attribute.is_empty())
+                .collect();
+            let mut line = format!("{}{}", "  ".repeat(depth), name.name());
+            if !attributes.is_empty() {
+                line.push_str(&format!(" ({})", attributes.join(" ")));
+            }
+            line
+        })
+        .collect()
+}
+
+/// Whether the message came through a mailing list, which sets `List-Id` or `List-Unsubscribe`
+/// (RFC 2919 and 2369).
+fn is_list_mail(headers: &[MailHeader]) -> bool {
+    headers.get_first_header("List-Id").is_some()
+        || headers.get_first_header("List-Unsubscribe").is_some()
+}
+
+/// Whether the message is `\Flagged` or its headers mark it as important
+/// (`X-Priority: 1` or `Importance: high`).
+fn is_important(flags: &[Flag], headers: &[MailHeader]) -> bool {
+    let x_priority = decoded_header(headers, "X-Priority")
+        .is_some_and(|value| value.split_whitespace().next() == Some("1"));
+    let importance = decoded_header(headers, "Importance")
+        .is_some_and(|value| value.eq_ignore_ascii_case("high"));
+    flags.contains(&Flag::Flagged) || x_priority || importance
+}
+
+fn find_text_plain<'a>(mail: &'a ParsedMail<'a>) -> Option<&'a ParsedMail<'a>> {
+    if mail.subparts.is_empty() {
+        let is_attachment =
+            mail.get_content_disposition().disposition == DispositionType::Attachment;
+        return (mail.ctype.mimetype == "text/plain" && !is_attachment).then_some(mail);
+    }
+    mail.subparts.iter().find_map(find_text_plain)
+}
+
+/// Connects to `addr`, from the local address `bind_address` if there is one.
+async fn connect_from(bind_address: Option<IpAddr>, addr: SocketAddr) -> Result<TcpStream> {
+    let Some(ip) = bind_address else {
+        return Ok(TcpStream::connect(addr).await?);
+    };
+    let socket = if ip.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket
+        .bind(SocketAddr::new(ip, 0))
+        .with_context(|| format!("failed to bind to {}", ip))?;
+    Ok(socket.connect(addr).await?)
+}
+
+/// `uids` as an IMAP sequence set, e.g. `1,2,5`.
+fn uid_set(uids: &[u32]) -> String {
+    uids.iter()
+        .map(u32::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// `name` as an IMAP quoted string, for commands async-imap doesn't quote itself.
+fn quoted(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
 }
 
-#[derive(Deserialize)]
-struct Account {
-    name: String,
-    server: String,
-    port: u16,
-    username: String,
-    password: String,
-    ntfy_url: String,
-    ntfy_topic: String,
-    ntfy_clickable_url: Option<String>,
+/// The IMAP extensions that change the commands we send, as far as the server offers them.
+#[derive(Clone, Copy, Default)]
+struct Extensions {
+    condstore: bool,
+    moves: bool,
+    uidplus: bool,
 }
 
-#[derive(Deserialize)]
-struct Config {
-    accounts: Vec<Account>,
+/// Which step of setting up a session failed, to tell a network blip from a credentials
+/// problem.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FailureKind {
+    Dns,
+    Connect,
+    Tls,
+    Auth,
+}
+
+impl FailureKind {
+    /// The kind recorded anywhere in `error`'s chain.
+    fn of(error: &anyhow::Error) -> Option<FailureKind> {
+        error
+            .downcast_ref::<SessionError>()
+            .map(|session_error| session_error.kind)
+    }
+
+    /// Wraps an error with this kind, unless an earlier step already gave it one.
+    fn wrap(self) -> impl FnOnce(anyhow::Error) -> anyhow::Error {
+        move |error| {
+            if FailureKind::of(&error).is_some() {
+                error
+            } else {
+                SessionError { kind: self, error }.into()
+            }
+        }
+    }
+
+    /// Follows the account name in failure notification titles.
+    fn description(self) -> &'static str {
+        match self {
+            FailureKind::Dns => "can't resolve the server",
+            FailureKind::Connect => "can't reach the server",
+            FailureKind::Tls => "TLS failed",
+            FailureKind::Auth => "login rejected",
+        }
+    }
+
+    fn tag(self) -> &'static str {
+        match self {
+            FailureKind::Dns => "dns",
+            FailureKind::Connect => "network",
+            FailureKind::Tls => "tls",
+            FailureKind::Auth => "auth",
+        }
+    }
+}
+
+/// An error with the `FailureKind` of the step it came from. It displays exactly like the
+/// error it wraps, causes included.
+#[derive(Debug)]
+struct SessionError {
+    kind: FailureKind,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl std::error::Error for SessionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.chain().nth(1)
+    }
+}
+
+/// Marks a failed login as rejected when the server answered NO or BAD, rather than the
+/// connection failing on the way.
+fn login_failed(error: anyhow::Error) -> anyhow::Error {
+    let rejected = error.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<async_imap::error::Error>(),
+            Some(async_imap::error::Error::No(_) | async_imap::error::Error::Bad(_))
+        )
+    });
+    if rejected {
+        FailureKind::Auth.wrap()(error)
+    } else {
+        error
+    }
+}
+
+/// Why an IDLE ended.
+enum IdleOutcome {
+    /// The server sent an untagged response, something in the mailbox may have changed.
+    NewData,
+    /// The IDLE was interrupted by our timer or by shutdown without hearing from the server.
+    Timeout,
+}
+
+/// Any transport we can speak IMAP over, so that TLS, STARTTLS and plaintext connections all end
+/// up as the same session type.
+trait ImapStream: AsyncRead + AsyncWrite + Unpin + Send + Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Debug> ImapStream for T {}
+
+type ImapSession = Session<Box<dyn ImapStream>>;
+
+/// A new message that passed the filters and is about to be notified.
+struct NewMail {
+    uid: u32,
+    from: String,
+    /// Only with `recipient_in_notification`.
+    to: Option<String>,
+    subject: String,
+    date: Option<String>,
+    preview: Option<String>,
+    priority: Priority,
+    /// Only known with `tag_attachments`, false otherwise.
+    has_attachments: bool,
+    /// An attachment is larger than `attachment_alert_bytes`.
+    large_attachment: bool,
+    /// Added by the filter rules that matched.
+    tags: Vec<String>,
 }
 
 struct UnseenMail {
     account: Account,
+    /// Built once so the HTTP client and its connections are reused across notifications.
+    notifier: Box<dyn Notifier>,
+    state: Arc<StateStore>,
+    metrics: Arc<Metrics>,
+    /// Adds a "Mark as read" button to new mail notifications when configured.
+    actions: Option<Arc<Actions>>,
+    /// Shared by every account with `max_concurrent_connections`, a permit is held while
+    /// connecting and logging in.
+    connections: Option<Arc<Semaphore>>,
+    dry_run: bool,
+    /// Set while we have a working session, heartbeats are only sent then.
+    connected: AtomicBool,
+    /// Whether we already warned that the server can't IDLE, so reconnects don't repeat it.
+    warned_no_idle: AtomicBool,
+    /// Same for a server that doesn't allow `notified_keyword`.
+    warned_no_keyword: AtomicBool,
+    /// What the server of the current session offers.
+    extensions: Mutex<Extensions>,
+    /// Signaled whenever new mail is notified, which restarts the heartbeat interval.
+    activity: Notify,
+    /// With `max_notifications_per_minute`, every mail notification takes a token.
+    rate_limit: Option<Mutex<TokenBucket>>,
+    /// Signaled when a notification was held back, so a summary is sent once tokens are back.
+    rate_limited: Notify,
+    /// Notifications that failed every attempt, sent before the next one.
+    queue: NotificationQueue,
+    /// Told how the first connection attempt went, for `[startup_notification]`.
+    first_connection: Mutex<Option<oneshot::Sender<Option<String>>>>,
+    /// Beaten while the account makes progress, with a systemd watchdog.
+    liveness: Option<Liveness>,
 }
 
 impl UnseenMail {
+    fn new(
+        account: Account,
+        state: Arc<StateStore>,
+        metrics: Arc<Metrics>,
+        actions: Option<Arc<Actions>>,
+        connections: Option<Arc<Semaphore>>,
+        dry_run: bool,
+    ) -> Result<Self> {
+        if account.danger_accept_invalid_certs {
+            warn!(
+                account = %account.name,
+                "certificate validation is DISABLED, the connection can be intercepted"
+            );
+        }
+        if let Some(target) = &account.on_notify_move_to {
+            warn!(
+                account = %account.name,
+                target = %target,
+                "notified mail is MOVED to another folder, this changes the mailbox"
+            );
+        }
+        if let Some(target) = &account.on_notify_copy_to {
+            warn!(
+                account = %account.name,
+                target = %target,
+                "notified mail is copied to another folder, this changes the mailbox"
+            );
+        }
+        let notifier = notifier::from_account(&account)
+            .with_context(|| format!("failed to set up the notifier of [{}]", account.name))?;
+        Ok(UnseenMail::with_notifier(
+            account,
+            notifier,
+            state,
+            metrics,
+            actions,
+            connections,
+            dry_run,
+        ))
+    }
+
+    /// Like `new`, but sends through `notifier` instead of the one the account configures.
+    fn with_notifier(
+        account: Account,
+        notifier: Box<dyn Notifier>,
+        state: Arc<StateStore>,
+        metrics: Arc<Metrics>,
+        actions: Option<Arc<Actions>>,
+        connections: Option<Arc<Semaphore>>,
+        dry_run: bool,
+    ) -> Self {
+        let rate_limit = account
+            .max_notifications_per_minute
+            .map(|limit| Mutex::new(TokenBucket::per_minute(limit.get(), Instant::now())));
+        let queue = NotificationQueue::open(
+            account.notify_queue_file.clone(),
+            account.notify_queue_size.get(),
+        );
+        UnseenMail {
+            account,
+            notifier,
+            state,
+            metrics,
+            actions,
+            connections,
+            dry_run,
+            connected: AtomicBool::new(false),
+            warned_no_idle: AtomicBool::new(false),
+            warned_no_keyword: AtomicBool::new(false),
+            extensions: Mutex::default(),
+            activity: Notify::new(),
+            rate_limit,
+            rate_limited: Notify::new(),
+            queue,
+            first_connection: Mutex::new(None),
+            liveness: None,
+        }
+    }
+
+    /// Sends how the first connection attempt went, see `startup::summary`.
+    fn report_first_connection(mut self, sender: Option<oneshot::Sender<Option<String>>>) -> Self {
+        self.first_connection = Mutex::new(sender);
+        self
+    }
+
+    /// Beats `liveness` while the account's loop makes progress, see `alive_while`.
+    fn report_liveness(mut self, liveness: Option<Liveness>) -> Self {
+        self.liveness = liveness;
+        self
+    }
+
+    fn beat(&self) {
+        if let Some(liveness) = &self.liveness {
+            liveness.beat(&self.account.name);
+        }
+    }
+
+    /// Runs `future`, a wait that is bound to end such as IDLE or a backoff, beating all along so
+    /// the watchdog doesn't take it for a stuck account.
+    async fn alive_while<F: std::future::Future>(&self, future: F) -> F::Output {
+        let Some(liveness) = &self.liveness else {
+            return future.await;
+        };
+        tokio::pin!(future);
+        let mut ticks = tokio::time::interval(liveness.tick());
+        loop {
+            tokio::select! {
+                output = &mut future => return output,
+                _ = ticks.tick() => self.beat(),
+            }
+        }
+    }
+
     async fn check_once(
         &self,
-        session: &mut Session<TlsStream<TcpStream>>,
-        last_notified: &mut u32,
+        session: &mut ImapSession,
+        folder: &str,
+        mailbox: &mut MailboxState,
     ) -> Result<()> {
-        let mut uids = session.uid_search("NEW 1:*").await?;
-        if uids.iter().all(|&uid| uid <= *last_notified) {
-            // there are no messages we haven't already notified about
-            uids.clear();
+        let condstore = self.extensions.lock().unwrap().condstore;
+        let selected = if condstore {
+            session.select_condstore(folder).await?
+        } else {
+            session.select(folder).await?
+        };
+        debug!(folder, uid_validity = ?selected.uid_validity, highest_modseq = ?selected.highest_modseq, "selected");
+        if selected.uid_validity != mailbox.uid_validity {
+            if mailbox.uid_validity.is_some() {
+                // the old UIDs mean nothing now, the Message-IDs still keep us from notifying
+                // the same mail twice
+                warn!(
+                    folder,
+                    old = ?mailbox.uid_validity,
+                    new = ?selected.uid_validity,
+                    "UIDVALIDITY changed, starting over"
+                );
+                mailbox.last_notified = 0;
+            }
+            mailbox.uid_validity = selected.uid_validity;
+            mailbox.highest_modseq = None;
+        }
+        // every change to the folder, new mail included, raises HIGHESTMODSEQ
+        let modseq = mailbox.highest_modseq.filter(|_| condstore);
+        if modseq.is_some() && modseq == selected.highest_modseq {
+            debug!(folder, "nothing changed since the last check");
+            return Ok(());
+        }
+        // only newer UIDs can be new to us, so the server doesn't have to look at the whole
+        // folder. A sequence set like the default `1:*` counts messages, not UIDs, hence `UID`.
+        let mut criteria = match mailbox.last_notified {
+            0 => self.account.search_criteria.clone(),
+            last => format!("{} UID {}:*", self.account.search_criteria, last + 1),
+        };
+        // whatever another instance already notified is marked on the server
+        let keyword = self.account.notified_keyword.as_deref().filter(|keyword| {
+            let allowed = selected.permanent_flags.iter().any(|flag| match flag {
+                Flag::MayCreate => true,
+                Flag::Custom(flag) => flag.eq_ignore_ascii_case(keyword),
+                _ => false,
+            });
+            if !allowed && !self.warned_no_keyword.swap(true, Ordering::Relaxed) {
+                warn!(
+                    folder,
+                    keyword, "server doesn't allow the keyword, not marking notified mail"
+                );
+            }
+            allowed
+        });
+        if let Some(keyword) = keyword {
+            criteria.push_str(&format!(" UNKEYWORD {}", keyword));
+        }
+        if let Some(modseq) = modseq {
+            criteria.push_str(&format!(" MODSEQ {}", modseq + 1));
+        }
+        let mut uids = session.uid_search(&criteria).await?;
+        mailbox.highest_modseq = selected.highest_modseq.filter(|_| condstore);
+        let last_notified = &mut mailbox.last_notified;
+        // `n:*` still matches the newest message when there is nothing above `n`, and UIDs only
+        // grow, so anything at or below `last_notified` has been seen before
+        let found = uids.len();
+        uids.retain(|&uid| uid > *last_notified);
+        if uids.len() < found {
+            // also what Dovecot does when it resets `\Recent` for a new session
+            debug!(
+                folder,
+                suppressed = found - uids.len(),
+                "skipped already notified UIDs"
+            );
+        }
+        // only advanced once the messages are fetched, or a failed fetch would skip them for good
+        let newest = std::cmp::max(*last_notified, uids.iter().cloned().max().unwrap_or(0));
+        if uids.is_empty() {
+            // an empty UID set is a syntax error, and there is nothing to fetch anyway
+            return Ok(());
         }
-        *last_notified = std::cmp::max(*last_notified, uids.iter().cloned().max().unwrap_or(0));
+        let mut uids: Vec<_> = uids.into_iter().collect();
+        uids.sort_unstable();
+        // after a long time offline only the newest messages are fetched, the rest are counted
+        let skipped_uids: Vec<_> = match self.account.max_notifications_per_check {
+            Some(max) if uids.len() > max.get() => uids.drain(..uids.len() - max.get()).collect(),
+            _ => Vec::new(),
+        };
+        let skipped = skipped_uids.len();
         let uids: Vec<_> = uids.into_iter().map(|v: u32| format!("{}", v)).collect();
-        let msg_stream = session.uid_fetch(uids.join(","), "RFC822.HEADER").await?;
+        let items = format!(
+            "({}{})",
+            if self.account.minimal_fetch {
+                MINIMAL_FETCH_ITEMS
+            } else {
+                FETCH_ITEMS
+            },
+            if self.account.tag_attachments || self.account.attachment_alert_bytes.is_some() {
+                " BODYSTRUCTURE"
+            } else {
+                ""
+            }
+        );
+        let msg_stream = session.uid_fetch(uids.join(","), &items).await?;
         let msgs = msg_stream.collect::<Vec<_>>().await;
-        println!("-- number of fetched msgs: {:?}", msgs.len());
+        debug!(folder, count = msgs.len(), "fetched messages");
+        // async-imap ends the stream quietly when the connection drops, so for missing messages
+        // NOOP tells a lost connection from mail expunged since the search
+        if msgs.len() < uids.len() {
+            session.noop().await?;
+        }
+        let mut new_mails = Vec::new();
+        let mut unparsable = Vec::new();
+        // remembered together with `newest`, once every message was fetched
+        let mut message_ids = Vec::new();
         for msg in msgs {
             let msg = msg?;
-            let msg = msg.header();
-            if msg.is_none() {
+            // `header()` covers both BODY[HEADER] and BODY[HEADER.FIELDS (...)] responses
+            let (Some(uid), Some(header)) = (msg.uid, msg.header()) else {
+                continue;
+            };
+            let flags = msg.flags().collect::<Vec<_>>();
+            // read on another device between the search and the fetch
+            if flags.contains(&Flag::Seen) {
+                debug!(folder, uid, "already read, skipping");
                 continue;
             }
-            match mailparse::parse_headers(msg.unwrap()) {
+            match mailparse::parse_headers(header) {
                 Ok((headers, _)) => {
-                    use mailparse::MailHeaderMap;
-                    let subject = headers
-                        .get_first_value("Subject")
+                    let subject = decoded_header(&headers, "Subject")
                         .unwrap_or_else(|| String::from("<no subject>"));
-                    println!("new mail: {}", subject);
-                    self.send_new_mail_notification(&subject).await.ok();
+                    let from = sender(&headers);
+                    if !self.account.vip_senders.is_empty() {
+                        // stricter than the filters, and a VIP is notified even if excluded
+                        if !filter::from_vip(&self.account.vip_senders, &headers) {
+                            debug!(folder, %from, %subject, "not from a VIP, skipping");
+                            continue;
+                        }
+                    } else if !self.account.filters.allows(&headers) {
+                        debug!(folder, %from, %subject, "new mail filtered out");
+                        continue;
+                    }
+                    if let Some(message_id) = decoded_header(&headers, "Message-ID") {
+                        if message_ids.contains(&message_id)
+                            || self.state.was_notified(&self.account.name, &message_id)
+                        {
+                            debug!(folder, %message_id, "already notified, skipping");
+                            continue;
+                        }
+                        message_ids.push(message_id);
+                    }
+                    let attachments = msg.bodystructure().map(attachment_sizes);
+                    let attachments = attachments.as_deref().unwrap_or_default();
+                    let large_attachment =
+                        self.account.attachment_alert_bytes.is_some_and(|limit| {
+                            attachments
+                                .iter()
+                                .any(|&size| u64::from(size) > limit.get())
+                        });
+                    // important mail is raised to high, but never lowered below the configured
+                    // priority, and being important beats being list mail
+                    let priority = if is_important(&flags, &headers) || large_attachment {
+                        std::cmp::max(self.account.ntfy_priority, Priority::High)
+                    } else if self.account.downgrade_list_mail && is_list_mail(&headers) {
+                        self.account.list_mail_priority
+                    } else {
+                        self.account.ntfy_priority
+                    };
+                    info!(folder, %from, %subject, ?priority, "new mail");
+                    new_mails.push(NewMail {
+                        uid,
+                        from,
+                        to: self
+                            .account
+                            .recipient_in_notification
+                            .then(|| recipients(&headers))
+                            .flatten(),
+                        subject,
+                        date: decoded_header(&headers, "Date").map(|date| {
+                            match self.account.display_timezone {
+                                Some(timezone) => display_date(&date, timezone),
+                                None => date,
+                            }
+                        }),
+                        preview: msg.text().and_then(|text| body_preview(header, text)),
+                        priority,
+                        has_attachments: self.account.tag_attachments && !attachments.is_empty(),
+                        large_attachment,
+                        tags: self.account.filters.tags(&headers),
+                    });
                 }
                 Err(e) => {
-                    println!("failed to parse headers of message: {:?}", e);
+                    warn!(folder, uid, error = ?e, "failed to parse headers of message");
+                    unparsable.push((uid, e));
                 }
             }
         }
+        mailbox.last_notified = newest;
+        // a dry run must not keep the next real run from notifying the mail
+        if !self.dry_run {
+            for message_id in &message_ids {
+                self.state.record_message_id(&self.account.name, message_id);
+            }
+        }
+        if !new_mails.is_empty() {
+            self.activity.notify_one();
+            self.metrics
+                .new_messages(&self.account.name, new_mails.len());
+        }
+        let unread = if self.account.show_unread_count && !new_mails.is_empty() {
+            match self.unread_count(session).await {
+                Ok(unread) => Some(unread),
+                Err(e) => {
+                    warn!(folder, "failed to count unread mail: {:#}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        if skipped > 0 {
+            info!(
+                folder,
+                skipped, "too many new messages, only notifying the newest"
+            );
+        }
+        if self.account.batch_notifications && new_mails.len() > 1 {
+            self.send_batch_notification(folder, &new_mails, unread)
+                .await
+                .ok();
+        } else {
+            for mail in &new_mails {
+                self.send_new_mail_notification(folder, mail, new_mails.len(), unread)
+                    .await
+                    .ok();
+            }
+        }
+        if skipped > 0 {
+            self.send_skipped_notification(folder, skipped).await.ok();
+        }
+        if self.account.notify_on_parse_error {
+            for (uid, error) in &unparsable {
+                self.send_parse_error_notification(folder, *uid, error)
+                    .await
+                    .ok();
+            }
+        }
+        if let Some(keyword) = keyword {
+            let notified: Vec<_> = new_mails
+                .iter()
+                .map(|mail| mail.uid)
+                .chain(skipped_uids)
+                .collect();
+            if let Err(e) = self.mark_notified(session, &notified, keyword).await {
+                warn!(folder, keyword, "failed to mark notified mail: {:#}", e);
+            }
+        }
+        let notified: Vec<_> = new_mails.iter().map(|mail| mail.uid).collect();
+        if let Err(e) = self.file_notified(session, folder, &notified).await {
+            warn!(folder, "failed to file notified mail: {:#}", e);
+        }
+        Ok(())
+    }
+
+    /// Moves or copies `uids` from the selected `folder` to `on_notify_move_to` or
+    /// `on_notify_copy_to`.
+    ///
+    /// Without MOVE a move is a COPY, `\Deleted` and an expunge. With UIDPLUS only these messages
+    /// are expunged, without it EXPUNGE also removes whatever else was marked `\Deleted` in the
+    /// folder, just like a mail client would.
+    async fn file_notified(
+        &self,
+        session: &mut ImapSession,
+        folder: &str,
+        uids: &[u32],
+    ) -> Result<()> {
+        let (target, remove) = match (
+            &self.account.on_notify_move_to,
+            &self.account.on_notify_copy_to,
+        ) {
+            (Some(target), _) => (target, true),
+            (None, Some(target)) => (target, false),
+            (None, None) => return Ok(()),
+        };
+        if uids.is_empty() || self.dry_run || target == folder {
+            return Ok(());
+        }
+        let set = uid_set(uids);
+        let extensions = *self.extensions.lock().unwrap();
+        if remove && extensions.moves {
+            session.uid_mv(&set, target).await?;
+            debug!(folder, target = %target, count = uids.len(), "moved notified mail");
+            return Ok(());
+        }
+        session.uid_copy(&set, quoted(target)).await?;
+        if !remove {
+            debug!(folder, target = %target, count = uids.len(), "copied notified mail");
+            return Ok(());
+        }
+        let updates = session
+            .uid_store(&set, "+FLAGS.SILENT (\\Deleted)")
+            .await?
+            .collect::<Vec<_>>()
+            .await;
+        for update in updates {
+            update?;
+        }
+        if extensions.uidplus {
+            let expunged = session.uid_expunge(&set).await?.collect::<Vec<_>>().await;
+            expunged.into_iter().collect::<Result<Vec<_>, _>>()?;
+        } else {
+            let expunged = session.expunge().await?.collect::<Vec<_>>().await;
+            expunged.into_iter().collect::<Result<Vec<_>, _>>()?;
+        }
+        debug!(folder, target = %target, count = uids.len(), "moved notified mail without MOVE");
+        Ok(())
+    }
+
+    /// Sets `notified_keyword` on `uids` in the selected folder.
+    async fn mark_notified(
+        &self,
+        session: &mut ImapSession,
+        uids: &[u32],
+        keyword: &str,
+    ) -> Result<()> {
+        if uids.is_empty() || self.dry_run {
+            return Ok(());
+        }
+        let updates = session
+            .uid_store(uid_set(uids), format!("+FLAGS.SILENT ({})", keyword))
+            .await?
+            .collect::<Vec<_>>()
+            .await;
+        for update in updates {
+            update?;
+        }
         Ok(())
     }
 
+    /// Counts the unseen messages in the selected folder. STATUS isn't meant for the selected
+    /// folder (RFC 3501, 6.3.10) and some servers answer it with a stale count, so this searches.
+    async fn unread_count(&self, session: &mut ImapSession) -> Result<u32> {
+        Ok(session.uid_search("UNSEEN").await?.len() as u32)
+    }
+
     async fn idle_wait(
         &self,
-        session: Session<TlsStream<TcpStream>>,
-    ) -> Result<Session<TlsStream<TcpStream>>> {
+        session: ImapSession,
+        timeout: Duration,
+        shutdown: &CancellationToken,
+    ) -> Result<(ImapSession, IdleOutcome)> {
         // init idle session
-        println!("-- initializing idle");
+        debug!("initializing idle");
         let mut idle = session.idle();
         idle.init().await?;
 
-        println!("-- idle async wait");
+        debug!("idle async wait");
         let (idle_wait, interrupt) = idle.wait();
 
-        task::spawn(async move {
-            println!("-- thread: waiting for 300 secs");
-            sleep(Duration::from_secs(300)).await;
-            println!("-- thread: waited 300 secs, now interrupting idle");
-            drop(interrupt);
-        });
+        let shutdown = shutdown.clone();
+        task::spawn(
+            async move {
+                debug!("waiting for {} secs", timeout.as_secs());
+                tokio::select! {
+                    _ = sleep(timeout) => {
+                        debug!("waited {} secs, now interrupting idle", timeout.as_secs());
+                    }
+                    _ = shutdown.cancelled() => {
+                        debug!("shutting down, now interrupting idle");
+                    }
+                }
+                drop(interrupt);
+            }
+            .in_current_span(),
+        );
 
         let idle_result = idle_wait.await?;
-        match idle_result {
+        let outcome = match idle_result {
             IdleResponse::ManualInterrupt => {
-                println!("-- IDLE manually interrupted");
+                debug!("IDLE manually interrupted");
+                IdleOutcome::Timeout
             }
             IdleResponse::Timeout => {
-                println!("-- IDLE timed out");
+                debug!("IDLE timed out");
+                IdleOutcome::Timeout
             }
             IdleResponse::NewData(data) => {
-                let s = String::from_utf8(data.borrow_raw().to_vec()).unwrap();
-                println!("-- IDLE data:\n{}", s);
+                // servers are free to send any bytes here, this is only for the log
+                debug!("IDLE data:\n{}", String::from_utf8_lossy(data.borrow_raw()));
+                IdleOutcome::NewData
             }
-        }
+        };
 
         // return the session after we are done with it
-        println!("-- sending DONE");
+        debug!("sending DONE");
         let session = idle.done().await?;
-        Ok(session)
+        Ok((session, outcome))
     }
 
-    async fn new_session(&self) -> Result<Session<TlsStream<TcpStream>>> {
+    /// Runs one network step of connecting, failing it after `connect_timeout_secs` so a hung
+    /// server ends up in the reconnect backoff instead of stalling the account forever.
+    async fn timed<T, E>(
+        &self,
+        step: &str,
+        future: impl std::future::Future<Output = Result<T, E>>,
+    ) -> Result<T>
+    where
+        E: Into<anyhow::Error>,
+    {
+        let timeout = self.account.connect_timeout();
+        match tokio::time::timeout(timeout, future).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(_) => bail!("{} timed out after {}s", step, timeout.as_secs()),
+        }
+    }
+
+    /// Connects to the first address of `server` in `address_family` that accepts, from
+    /// `bind_address` if set.
+    async fn connect_direct(&self) -> Result<TcpStream> {
         let account = &self.account;
-        let tcp_stream = TcpStream::connect((account.server.as_str(), account.port)).await?;
-        let tls = async_native_tls::TlsConnector::new();
-        let tls_stream = tls.connect(account.server.as_str(), tcp_stream).await?;
-        let client = async_imap::Client::new(tls_stream);
-        println!("-- connected to {}:{}", account.server, account.port);
-
-        let mut session = client
-            .login(account.username.as_str(), account.password.as_str())
+        let bind_address = account.bind_address;
+        // a socket bound to an IPv4 address can only reach IPv4 addresses and the other way round
+        let family = bind_address.map_or(account.address_family, AddressFamily::of);
+        let addrs = lookup_host((account.server.as_str(), account.port))
             .await
-            .map_err(|e| e.0)?;
-        println!("-- logged in a {}", account.username);
+            .with_context(|| format!("failed to resolve {}", account.server))
+            .map_err(FailureKind::Dns.wrap())?
+            .filter(|addr| family.matches(addr));
+        let mut last_error = None;
+        for addr in addrs {
+            match connect_from(bind_address, addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    debug!(%addr, "connection failed: {:#}", e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        match last_error {
+            Some(e) => Err(e),
+            None => Err(FailureKind::Dns.wrap()(anyhow::anyhow!(
+                "{} has no {}address",
+                account.server,
+                match family {
+                    AddressFamily::Any => "",
+                    AddressFamily::Ipv4 => "IPv4 ",
+                    AddressFamily::Ipv6 => "IPv6 ",
+                }
+            ))),
+        }
+    }
 
-        let capabilities = session.capabilities().await?;
-        if !capabilities.has_str("IDLE") {
-            panic!("server does not support IDLE (in [{}])", self.account.name);
+    async fn connect(&self) -> Result<Box<dyn ImapStream>> {
+        let account = &self.account;
+        let tcp_stream = match account.proxy()? {
+            Some(proxy) => {
+                self.timed("connecting", proxy.connect(&account.server, account.port))
+                    .await
+            }
+            None => self.timed("connecting", self.connect_direct()).await,
+        }
+        .map_err(FailureKind::Connect.wrap())?;
+        if let Some(keepalive) = account.keepalive() {
+            // lets the kernel notice a dead link even while we sit in IDLE without sending anything
+            let params = TcpKeepalive::new()
+                .with_time(keepalive)
+                .with_interval(keepalive);
+            SockRef::from(&tcp_stream)
+                .set_tcp_keepalive(&params)
+                .context("failed to enable TCP keepalive")?;
+        }
+        let tls = account.tls_connector()?;
+        match account.encryption {
+            Encryption::Tls => Ok(Box::new(
+                self.timed(
+                    "TLS handshake",
+                    tls.connect(account.server.as_str(), tcp_stream),
+                )
+                .await
+                .map_err(FailureKind::Tls.wrap())?,
+            )),
+            Encryption::Starttls => {
+                let mut client = async_imap::Client::new(tcp_stream);
+                // the greeting has to be consumed before we can issue STARTTLS
+                self.read_greeting(&mut client).await?;
+                self.timed(
+                    "STARTTLS",
+                    client.run_command_and_check_ok("STARTTLS", None),
+                )
+                .await
+                .map_err(FailureKind::Tls.wrap())?;
+                let tcp_stream = client.into_inner();
+                Ok(Box::new(
+                    self.timed(
+                        "TLS handshake",
+                        tls.connect(account.server.as_str(), tcp_stream),
+                    )
+                    .await
+                    .map_err(FailureKind::Tls.wrap())?,
+                ))
+            }
+            Encryption::None => Ok(Box::new(tcp_stream)),
         }
+    }
 
-        session.select("INBOX").await?;
-        println!("-- INBOX selected");
+    async fn new_session(&self) -> Result<ImapSession> {
+        let account = &self.account;
+        let _permit = match &self.connections {
+            Some(connections) => {
+                if connections.available_permits() == 0 {
+                    debug!("waiting for other accounts to finish connecting");
+                }
+                Some(connections.acquire().await?)
+            }
+            None => None,
+        };
+        let switch = CompressSwitch::default();
+        let mut stream = self.connect().await?;
+        if account.compress {
+            stream = Box::new(Compressible::new(stream, switch.clone()));
+        }
+        let mut client = async_imap::Client::new(stream);
+        debug!(server = %account.server, port = account.port, "connected");
+        if account.encryption != Encryption::Starttls {
+            self.read_greeting(&mut client).await?;
+        }
+
+        let mut session = match &account.auth {
+            Auth::Password => {
+                let password = account.password_source()?.resolve().await?;
+                match account.auth_mechanism {
+                    AuthMechanism::Login => self
+                        .timed("logging in", async {
+                            client
+                                .login(account.username.as_str(), password.as_str())
+                                .await
+                                .map_err(|e| e.0)
+                        })
+                        .await
+                        .map_err(login_failed)?,
+                    AuthMechanism::Plain | AuthMechanism::CramMd5 => {
+                        let mechanism = account.auth_mechanism;
+                        let name = mechanism.sasl_name().unwrap_or_default();
+                        let user = account.username.clone();
+                        self.timed("logging in", async {
+                            if !auth_mechanisms(&mut client).await?.contains(name) {
+                                return Err(FailureKind::Auth.wrap()(anyhow::anyhow!(
+                                    "server does not offer AUTH={}, try `auth_mechanism = \"login\"`",
+                                    name
+                                )));
+                            }
+                            let session = if mechanism == AuthMechanism::Plain {
+                                client.authenticate(name, &Plain { user, password }).await
+                            } else {
+                                client.authenticate(name, &CramMd5 { user, password }).await
+                            };
+                            session.map_err(|e| anyhow::Error::from(e.0))
+                        })
+                        .await
+                        .map_err(login_failed)?
+                    }
+                }
+            }
+            Auth::Xoauth2 { .. } => {
+                let xoauth2 = XOAuth2 {
+                    user: account.username.clone(),
+                    access_token: account.auth.access_token().await?,
+                };
+                self.timed("logging in", async {
+                    client
+                        .authenticate("XOAUTH2", &xoauth2)
+                        .await
+                        .map_err(|e| e.0)
+                })
+                .await
+                .map_err(login_failed)?
+            }
+        };
+        info!(username = %account.username, "logged in");
+        // servers often offer more once logged in, so this is the list that matters
+        let mut deflate = false;
+        match session.capabilities().await {
+            Ok(capabilities) => {
+                deflate = capabilities.has_str("COMPRESS=DEFLATE");
+                let extensions = Extensions {
+                    condstore: capabilities.has_str("CONDSTORE"),
+                    moves: capabilities.has_str("MOVE"),
+                    uidplus: capabilities.has_str("UIDPLUS"),
+                };
+                *self.extensions.lock().unwrap() = extensions;
+                if tracing::enabled!(tracing::Level::DEBUG) {
+                    use async_imap::types::Capability;
+                    let mut names: Vec<_> = capabilities
+                        .iter()
+                        .map(|capability| match capability {
+                            Capability::Imap4rev1 => String::from("IMAP4rev1"),
+                            Capability::Auth(mechanism) => format!("AUTH={}", mechanism),
+                            Capability::Atom(atom) => atom.clone(),
+                        })
+                        .collect();
+                    names.sort_unstable();
+                    debug!(
+                        idle = capabilities.has_str("IDLE"),
+                        "move" = extensions.moves,
+                        condstore = extensions.condstore,
+                        uidplus = extensions.uidplus,
+                        deflate,
+                        capabilities = %names.join(" "),
+                        "server capabilities"
+                    );
+                }
+            }
+            Err(e) => {
+                debug!("failed to list capabilities: {}", e);
+                *self.extensions.lock().unwrap() = Extensions::default();
+            }
+        }
+        if account.compress {
+            if deflate {
+                self.compress(&mut session, &switch).await?;
+            } else {
+                debug!("server does not offer COMPRESS=DEFLATE, staying uncompressed");
+            }
+        }
         Ok(session)
     }
 
+    /// Sends `COMPRESS DEFLATE` and compresses everything after its OK. A server that refuses
+    /// leaves the connection as it was.
+    async fn compress(&self, session: &mut ImapSession, switch: &CompressSwitch) -> Result<()> {
+        let result = self
+            .timed(
+                "COMPRESS",
+                session.run_command_and_check_ok("COMPRESS DEFLATE"),
+            )
+            .await;
+        match result {
+            Ok(()) => {
+                switch.enable();
+                debug!("compression enabled");
+                Ok(())
+            }
+            Err(e) => match e.downcast_ref::<async_imap::error::Error>() {
+                Some(async_imap::error::Error::No(_) | async_imap::error::Error::Bad(_)) => {
+                    warn!(
+                        "server refused COMPRESS DEFLATE, staying uncompressed: {}",
+                        e
+                    );
+                    Ok(())
+                }
+                _ => Err(e),
+            },
+        }
+    }
+
+    /// Reads the server greeting, which has to come before the first command.
+    async fn read_greeting<T: ImapStream>(&self, client: &mut async_imap::Client<T>) -> Result<()> {
+        let greeting = self
+            .timed("waiting for the greeting", async {
+                client
+                    .read_response()
+                    .await
+                    .context("connection closed before the server greeting")?
+                    .map_err(anyhow::Error::from)
+            })
+            .await?;
+        // the raw buffer runs past the response, the greeting is its first line
+        let raw = greeting.borrow_raw();
+        let line = raw.split(|&byte| byte == b'\n').next().unwrap_or(raw);
+        debug!(
+            greeting = %String::from_utf8_lossy(line).trim_end(),
+            "server greeting"
+        );
+        Ok(())
+    }
+
+    /// Logs in and returns every folder the server lists, see `folder_tree`.
+    async fn list_folders(&self) -> Result<Vec<String>> {
+        let mut session = self.new_session().await?;
+        let names = session
+            .list(None, Some("*"))
+            .await?
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+        self.logout(session).await;
+        let delimiter = names.iter().find_map(Name::delimiter);
+        let mut lines = vec![match delimiter {
+            Some(delimiter) => format!("hierarchy delimiter {:?}", delimiter),
+            None => String::from("no hierarchy delimiter"),
+        }];
+        lines.extend(folder_tree(&names));
+        Ok(lines)
+    }
+
+    /// Checks every folder once and saves the progress of each.
+    ///
+    /// UIDs are per-mailbox, so every folder keeps its own `last_notified`. The IDLE folder is
+    /// checked last so that it is still selected when we start idling on it.
+    async fn check_folders(
+        &self,
+        session: &mut ImapSession,
+        mailboxes: &mut HashMap<String, MailboxState>,
+    ) -> Result<()> {
+        let Some((idle_folder, other_folders)) = self.account.folders.split_first() else {
+            bail!("no folders configured for [{}]", self.account.name);
+        };
+        self.send_queued().await;
+        for folder in other_folders.iter().chain([idle_folder]) {
+            let folder = &folder.name;
+            let mailbox = mailboxes.entry(folder.clone()).or_default();
+            self.check_once(session, folder, mailbox).await?;
+            if self.dry_run {
+                continue;
+            }
+            if let Err(e) = self.state.update(&self.account.name, folder, mailbox) {
+                error!("failed to save state: {:#}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits out `poll_interval` on a server without IDLE. With `keepalive_secs` a NOOP is sent
+    /// every so often, so a dead connection fails (and reconnects) long before the next poll.
+    async fn poll_wait(
+        &self,
+        session: &mut ImapSession,
+        poll_interval: Duration,
+        shutdown: &CancellationToken,
+    ) -> Result<()> {
+        let deadline = Instant::now() + poll_interval;
+        loop {
+            let wake = match self.account.keepalive() {
+                Some(keepalive) => std::cmp::min(Instant::now() + keepalive, deadline),
+                None => deadline,
+            };
+            tokio::select! {
+                _ = tokio::time::sleep_until(wake) => {}
+                _ = shutdown.cancelled() => return Ok(()),
+            }
+            if wake >= deadline {
+                return Ok(());
+            }
+            debug!("sending NOOP");
+            session.noop().await.context("NOOP failed")?;
+        }
+    }
+
+    /// Logs out to be nice to the server. The connection may already be dead after a failure, so
+    /// this gives up after a few seconds and only logs errors, the caller's own result matters.
+    async fn logout(&self, mut session: ImapSession) {
+        match tokio::time::timeout(LOGOUT_TIMEOUT, session.logout()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => debug!("logout failed: {}", e),
+            Err(_) => debug!("logout timed out after {}s", LOGOUT_TIMEOUT.as_secs()),
+        }
+    }
+
     async fn loop_check(
         &self,
-        mut session: Session<TlsStream<TcpStream>>,
-        last_notified: &mut u32,
+        mut session: ImapSession,
+        mailboxes: &mut HashMap<String, MailboxState>,
+        shutdown: &CancellationToken,
     ) -> Result<()> {
+        let poll_interval = self.account.poll_interval();
+        let idle_timeout = self.account.idle_timeout();
+        let idle_supported = session.capabilities().await?.has_str("IDLE");
+        if !idle_supported && !self.warned_no_idle.swap(true, Ordering::Relaxed) {
+            warn!(
+                "server does not support IDLE, polling every {} secs instead",
+                poll_interval.as_secs()
+            );
+        }
+        let mut last_check = Instant::now();
+        let mut check_due = true;
         loop {
-            let check_result = self.check_once(&mut session, last_notified).await;
-            if check_result.is_err() {
-                // be nice to the server and log out
-                eprintln!("-- check failed and logging out");
-                session.logout().await?;
+            self.beat();
+            if check_due {
+                if let Err(e) = self.check_folders(&mut session, mailboxes).await {
+                    warn!("check failed and logging out");
+                    self.logout(session).await;
+                    return Err(e);
+                }
+                last_check = Instant::now();
+            }
+            let outcome = if idle_supported {
+                // waking up for the poll doubles as renewing the IDLE, so the poll is never late
+                let until_poll = poll_interval.saturating_sub(last_check.elapsed());
+                // a failed IDLE takes the session with it, dropping it closes the connection
+                let (idle_session, outcome) = self
+                    .alive_while(self.idle_wait(
+                        session,
+                        std::cmp::min(idle_timeout, until_poll),
+                        shutdown,
+                    ))
+                    .await?;
+                session = idle_session;
+                outcome
+            } else {
+                let wait = self.alive_while(self.poll_wait(&mut session, poll_interval, shutdown));
+                if let Err(e) = wait.await {
+                    self.logout(session).await;
+                    return Err(e);
+                }
+                IdleOutcome::Timeout
+            };
+            if shutdown.is_cancelled() {
+                info!("shutting down and logging out");
+                self.logout(session).await;
+                return Ok(());
+            }
+            // a timeout before the poll is due only means the IDLE has to be renewed, once it is
+            // due the mailbox is searched in case the server missed telling us about something
+            check_due = match outcome {
+                IdleOutcome::NewData => true,
+                IdleOutcome::Timeout => last_check.elapsed() >= poll_interval,
+            };
+            if !check_due {
+                debug!("nothing new, renewing IDLE without checking");
             }
-            check_result?;
-            session = self.idle_wait(session).await?;
         }
     }
 
-    async fn send_new_mail_notification(&self, subject: &str) -> Result<()> {
-        let dispatcher = Dispatcher::builder(&self.account.ntfy_url).build()?;
-        let mut payload = Payload::new(&self.account.ntfy_topic)
-            .title(format!("@{} has new mail", self.account.name))
-            .message(subject)
-            .priority(Priority::Default);
-        if let Some(ntfy_clickable_url) = &self.account.ntfy_clickable_url {
-            payload = payload.click(Url::parse(ntfy_clickable_url).unwrap());
+    async fn send_new_mail_notification(
+        &self,
+        folder: &str,
+        mail: &NewMail,
+        count: usize,
+        unread: Option<u32>,
+    ) -> Result<()> {
+        let mut message = format!("From: {}\n", mail.from);
+        if let Some(to) = &mail.to {
+            message.push_str(&format!("To: {}\n", to));
         }
-        dispatcher.send(&payload).await?;
-        Ok(())
+        if let Some(date) = &mail.date {
+            message.push_str(&format!("Date: {}\n", date));
+        }
+        message.push_str(&format!("Subject: {}", mail.subject));
+        if let Some(preview) = &mail.preview {
+            message.push_str(&format!("\n\n{}", preview));
+        }
+        let template = self
+            .account
+            .folder(folder)
+            .and_then(|folder| folder.title_template.as_deref())
+            .or(self.account.title_template.as_deref())
+            .unwrap_or("@{account} has new mail in {folder}");
+        let mut title = fill_placeholders(template, |name| match name {
+            "account" => Some(self.account.name.clone()),
+            "folder" => Some(folder.to_string()),
+            "subject" => Some(mail.subject.clone()),
+            "from" => Some(mail.from.clone()),
+            "count" => Some(count.to_string()),
+            _ => None,
+        });
+        title.push_str(&unread_suffix(unread));
+        let mut notification = Notification::new(title, message, mail.priority)
+            .topic(self.folder_topic(folder))
+            .click(self.click_url(folder, mail.uid))
+            .mail(MailDetails {
+                from: mail.from.clone(),
+                to: mail.to.clone(),
+                subject: mail.subject.clone(),
+                date: mail.date.clone(),
+                preview: mail.preview.clone(),
+            });
+        if mail.has_attachments {
+            notification = notification.tag("paperclip");
+        }
+        if mail.large_attachment {
+            notification = notification.tag("package");
+        }
+        for tag in self.account.ntfy_tags.iter().chain(&mail.tags) {
+            notification = notification.tag(tag.as_str());
+        }
+        if let Some(actions) = &self.actions {
+            notification =
+                notification.action(actions.mark_seen_action(&self.account.name, folder, mail.uid));
+        }
+        self.notify_mail(&notification, 1).await
+    }
+
+    async fn send_batch_notification(
+        &self,
+        folder: &str,
+        mails: &[NewMail],
+        unread: Option<u32>,
+    ) -> Result<()> {
+        let message = mails
+            .iter()
+            .map(|mail| format!("{}: {}", mail.from, mail.subject))
+            .collect::<Vec<_>>()
+            .join("\n");
+        // a batch can only link to one message, the newest is the most likely one to be
+        // interesting
+        let newest = mails.iter().map(|mail| mail.uid).max().unwrap_or_default();
+        let mut notification = Notification::new(
+            format!(
+                "@{} has {} new messages in {}{}",
+                self.account.name,
+                mails.len(),
+                folder,
+                unread_suffix(unread)
+            ),
+            message,
+            // the summary is as urgent as the most important message in it
+            mails
+                .iter()
+                .map(|mail| mail.priority)
+                .max()
+                .unwrap_or(self.account.ntfy_priority),
+        )
+        .topic(self.folder_topic(folder))
+        .click(self.click_url(folder, newest));
+        if mails.iter().any(|mail| mail.has_attachments) {
+            notification = notification.tag("paperclip");
+        }
+        if mails.iter().any(|mail| mail.large_attachment) {
+            notification = notification.tag("package");
+        }
+        let rule_tags = mails.iter().flat_map(|mail| &mail.tags);
+        for tag in self.account.ntfy_tags.iter().chain(rule_tags) {
+            notification = notification.tag(tag.as_str());
+        }
+        self.notify_mail(&notification, mails.len()).await
+    }
+
+    /// Tells about mail that was never notified because its headers can't be parsed, with
+    /// `notify_on_parse_error`.
+    async fn send_parse_error_notification(
+        &self,
+        folder: &str,
+        uid: u32,
+        error: &mailparse::MailParseError,
+    ) -> Result<()> {
+        let notification = Notification::new(
+            format!(
+                "@{} received mail it couldn't parse in {}",
+                self.account.name, folder
+            ),
+            format!("UID {}: {}, check it manually", uid, error),
+            Priority::Low,
+        )
+        .tag("warning")
+        .topic(self.folder_topic(folder))
+        .click(self.click_url(folder, uid));
+        self.notify_mail(&notification, 1).await
+    }
+
+    /// Summarizes the older messages `max_notifications_per_check` left out.
+    async fn send_skipped_notification(&self, folder: &str, skipped: usize) -> Result<()> {
+        let notification = Notification::new(
+            format!(
+                "@{} has {} more new messages in {}",
+                self.account.name, skipped, folder
+            ),
+            format!(
+                "and {} more, only the newest messages were notified",
+                skipped
+            ),
+            self.account.ntfy_priority,
+        )
+        .topic(self.folder_topic(folder));
+        self.notify_mail(&notification, skipped).await
+    }
+
+    /// Sends a notification about `messages` new messages, unless `max_notifications_per_minute`
+    /// was reached. Then the messages are only counted for `send_rate_limited_summary`.
+    async fn notify_mail(&self, notification: &Notification, messages: usize) -> Result<()> {
+        if let Some(rate_limit) = &self.rate_limit {
+            let mut bucket = rate_limit.lock().unwrap();
+            if !bucket.try_take(Instant::now()) {
+                bucket.suppressed += messages;
+                debug!(
+                    suppressed = bucket.suppressed,
+                    "rate limited, holding back notification"
+                );
+                self.rate_limited.notify_one();
+                return Ok(());
+            }
+        }
+        self.notify(notification).await
+    }
+
+    /// Sends one notification for the messages the rate limit held back, if there are any.
+    async fn send_rate_limited_summary(&self) -> Result<()> {
+        let Some(rate_limit) = &self.rate_limit else {
+            return Ok(());
+        };
+        let suppressed = std::mem::take(&mut rate_limit.lock().unwrap().suppressed);
+        if suppressed == 0 {
+            return Ok(());
+        }
+        let notification = Notification::new(
+            format!(
+                "@{} has {} more messages (rate limited)",
+                self.account.name, suppressed
+            ),
+            format!(
+                "`max_notifications_per_minute` was reached, {} messages were not notified",
+                suppressed
+            ),
+            self.account.ntfy_priority,
+        );
+        self.notify(&notification).await
+    }
+
+    /// Sends the summary of held back messages as soon as the rate limit has a token again, and
+    /// on shutdown.
+    async fn flush_rate_limited(&self, shutdown: &CancellationToken) {
+        let Some(rate_limit) = &self.rate_limit else {
+            return;
+        };
+        loop {
+            tokio::select! {
+                _ = self.rate_limited.notified() => {}
+                _ = shutdown.cancelled() => break,
+            }
+            loop {
+                let wait = rate_limit.lock().unwrap().until_available(Instant::now());
+                tokio::select! {
+                    _ = sleep(wait) => {}
+                    _ = shutdown.cancelled() => break,
+                }
+                // new mail may have taken the token in the meantime
+                if rate_limit.lock().unwrap().try_take(Instant::now()) {
+                    break;
+                }
+            }
+            if shutdown.is_cancelled() {
+                break;
+            }
+            self.send_rate_limited_summary().await.ok();
+        }
+        // nothing held back is lost on shutdown, the limit doesn't matter anymore
+        self.send_rate_limited_summary().await.ok();
+    }
+
+    /// The `ntfy_topic` override of `folder`, if it has one.
+    fn folder_topic(&self, folder: &str) -> Option<String> {
+        self.account.folder(folder)?.ntfy_topic.clone()
+    }
+
+    /// Fills the `{account}`, `{folder}` and `{uid}` placeholders of `ntfy_clickable_url`, each
+    /// value URL-encoded. A URL without placeholders is used as is.
+    fn click_url(&self, folder: &str, uid: u32) -> Option<String> {
+        let encode = |s: &str| utf8_percent_encode(s, NON_ALPHANUMERIC).to_string();
+        let url = self.account.ntfy_clickable_url.as_ref()?;
+        Some(
+            url.replace("{account}", &encode(&self.account.name))
+                .replace("{folder}", &encode(folder))
+                .replace("{uid}", &uid.to_string()),
+        )
     }
 
     async fn report_error(&self, error_msg: &str) -> Result<()> {
-        let dispatcher = Dispatcher::builder(&self.account.ntfy_url).build()?;
-        let payload = Payload::new(&self.account.ntfy_topic)
-            .title(format!("@{} connection failed", self.account.name))
-            .message(error_msg)
-            .tags(vec!["warning".into()])
-            .priority(Priority::Default);
-        dispatcher.send(&payload).await?;
+        self.report_failure(None, error_msg).await
+    }
+
+    /// Like `report_error`, with what failed in the title and as a tag when it is known. A
+    /// rejected login is high priority, it needs the user to do something.
+    async fn report_failure(&self, kind: Option<FailureKind>, error_msg: &str) -> Result<()> {
+        let title = match kind {
+            Some(kind) => format!("@{} {}", self.account.name, kind.description()),
+            None => format!("@{} connection failed", self.account.name),
+        };
+        let priority = if kind == Some(FailureKind::Auth) {
+            Priority::High
+        } else {
+            Priority::Default
+        };
+        let mut notification = Notification::new(title, error_msg, priority).tag("warning");
+        if let Some(kind) = kind {
+            notification = notification.tag(kind.tag());
+        }
+        self.notify(&notification).await
+    }
+
+    async fn report_heartbeat(&self) -> Result<()> {
+        let notification = Notification::new(
+            format!("still watching @{}", self.account.name),
+            format!(
+                "connected to {}:{}, no new mail for a while",
+                self.account.server, self.account.port
+            ),
+            Priority::Low,
+        )
+        .tag("heartbeat");
+        self.notify(&notification).await
+    }
+
+    async fn report_recovery(&self) -> Result<()> {
+        let notification = Notification::new(
+            format!("@{} connection restored", self.account.name),
+            format!(
+                "reconnected to {}:{}",
+                self.account.server, self.account.port
+            ),
+            Priority::Low,
+        )
+        .tag("white_check_mark");
+        self.notify(&notification).await
+    }
+
+    async fn notify(&self, notification: &Notification) -> Result<()> {
+        if self.dry_run {
+            info!(
+                title = %notification.title,
+                message = %notification.message,
+                "dry run, not sending notification"
+            );
+            return Ok(());
+        }
+        self.send_queued().await;
+        if let Err(e) = self.send_with_retries(notification).await {
+            error!(
+                title = %notification.title,
+                subject = ?notification.mail.as_ref().map(|mail| &mail.subject),
+                "failed to send notification, queueing it: {:#}",
+                e
+            );
+            self.queue.push(notification.clone());
+            return Err(e);
+        }
         Ok(())
     }
 
-    async fn run(self) {
+    /// Tries `notify_attempts` times, waiting twice as long after every failure.
+    async fn send_with_retries(&self, notification: &Notification) -> Result<()> {
+        let mut delay = NOTIFY_RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            match self.notifier.notify(notification).await {
+                Ok(()) => {
+                    self.metrics.notification_sent(&self.account.name);
+                    return Ok(());
+                }
+                Err(e) if attempt < self.account.notify_attempts.get() => {
+                    warn!(
+                        attempt,
+                        "failed to send notification, retrying in {}s: {:#}",
+                        delay.as_secs(),
+                        e
+                    );
+                    self.alive_while(sleep(delay)).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Sends the queued notifications that failed before, oldest first, and stops at the first
+    /// one that still fails since the notifier is evidently still down.
+    async fn send_queued(&self) {
+        if self.dry_run || self.queue.is_empty() {
+            return;
+        }
+        let mut queued = self.queue.take();
+        for notification in &mut queued {
+            // the queue file has no tokens, and `[actions]` may be gone since
+            match &self.actions {
+                Some(actions) => {
+                    for action in &mut notification.actions {
+                        action.headers = actions.headers();
+                    }
+                }
+                None => notification.actions.clear(),
+            }
+        }
+        let mut sent = 0;
+        while let Some(notification) = queued.front() {
+            if let Err(e) = self.notifier.notify(notification).await {
+                debug!(title = %notification.title, "queued notification still fails: {:#}", e);
+                break;
+            }
+            self.metrics.notification_sent(&self.account.name);
+            queued.pop_front();
+            sent += 1;
+        }
+        if sent > 0 {
+            info!("sent {} queued notifications", sent);
+        }
+        self.queue.put_back(queued);
+    }
+
+    /// Connects, checks every folder a single time and logs out.
+    async fn run_once(self) -> Result<()> {
+        let mut session = self.new_session().await?;
+        let mut mailboxes = self.state.mailboxes(&self.account.name);
+        let check_result = self.check_folders(&mut session, &mut mailboxes).await;
+        self.logout(session).await;
+        // nothing is left to wait for the rate limit
+        self.send_rate_limited_summary().await.ok();
+        check_result
+    }
+
+    /// Runs the account and restarts it with backoff whenever it panics, so a bug hit by one
+    /// account doesn't take the others down.
+    async fn supervise(&self, shutdown: CancellationToken) {
         let mut wait = 1u64;
-        let mut last_notified = 0;
         loop {
-            let session = self.new_session().await;
+            let panic = match AssertUnwindSafe(self.run(shutdown.clone()))
+                .catch_unwind()
+                .await
+            {
+                Ok(()) => return,
+                Err(panic) => panic,
+            };
+            let message = panic
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            error!("account crashed: {}; restarting after {wait}s ...", message);
+            self.set_connected(false);
+            self.report_error(&format!(
+                "crashed: {}; restarting after {wait}s ...",
+                message
+            ))
+            .await
+            .ok();
+            tokio::select! {
+                _ = self.alive_while(sleep(self.account.backoff_delay(wait))) => {}
+                _ = shutdown.cancelled() => return,
+            }
+            wait = std::cmp::min(wait * 2, self.account.max_backoff_secs.get());
+        }
+    }
+
+    async fn run(&self, shutdown: CancellationToken) {
+        tokio::join!(
+            self.watch(&shutdown),
+            self.heartbeat(&shutdown),
+            self.flush_rate_limited(&shutdown)
+        );
+    }
+
+    /// Sends a heartbeat after every `heartbeat_interval_secs` without new mail, as long as the
+    /// account is connected.
+    async fn heartbeat(&self, shutdown: &CancellationToken) {
+        let Some(interval) = self.account.heartbeat_interval() else {
+            return;
+        };
+        loop {
+            tokio::select! {
+                _ = sleep(interval) => {
+                    if self.connected.load(Ordering::Relaxed) {
+                        self.report_heartbeat().await.ok();
+                    } else {
+                        debug!("not connected, skipping heartbeat");
+                    }
+                }
+                _ = self.activity.notified() => {}
+                _ = shutdown.cancelled() => return,
+            }
+        }
+    }
+
+    fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::Relaxed);
+        self.metrics.set_connected(&self.account.name, connected);
+    }
+
+    /// Keeps a session open and reconnects with backoff until `shutdown` is cancelled.
+    async fn watch(&self, shutdown: &CancellationToken) {
+        let mut wait = 1u64;
+        // whether the user was told about the outage and should hear about the recovery
+        let mut error_reported = false;
+        // when connecting started failing, reset by every successful connection
+        let mut failing_since = None;
+        let mut mailboxes = self.state.mailboxes(&self.account.name);
+        let mut first_attempt = true;
+        while !shutdown.is_cancelled() {
+            self.beat();
+            if !std::mem::take(&mut first_attempt) {
+                self.metrics.reconnect(&self.account.name);
+            }
+            // every network step of connecting is timed
+            let session = tokio::select! {
+                session = self.alive_while(self.new_session()) => session,
+                _ = shutdown.cancelled() => break,
+            };
+            if let Some(first_connection) = self.first_connection.lock().unwrap().take() {
+                let failure = session.as_ref().err().map(|e| {
+                    FailureKind::of(e).map_or("connection failed", FailureKind::description)
+                });
+                first_connection.send(failure.map(str::to_string)).ok();
+            }
             match session {
                 Ok(session) => {
-                    self.loop_check(session, &mut last_notified).await.ok();
+                    wait = 1;
+                    failing_since = None;
+                    self.set_connected(true);
+                    if std::mem::take(&mut error_reported) {
+                        self.report_recovery().await.ok();
+                    }
+                    let result = self.loop_check(session, &mut mailboxes, shutdown).await;
+                    self.set_connected(false);
+                    if let Err(e) = result {
+                        warn!("session failed: {:#}", e);
+                    }
                 }
                 Err(e) => {
-                    eprintln!(
-                        "connection failed: {}; trying to reconnect after {wait}s ...",
-                        e
+                    let kind = FailureKind::of(&e);
+                    let failing_since = *failing_since.get_or_insert_with(Instant::now);
+                    if kind == Some(FailureKind::Auth) {
+                        // retrying the same credentials quickly won't help and may get the
+                        // account locked
+                        if self.account.stop_on_auth_failure {
+                            error!("login rejected, not trying again: {:#}", e);
+                            self.report_failure(
+                                kind,
+                                &format!("{:#}; not trying again until the account's config changes or unseenmail restarts", e),
+                            )
+                            .await
+                            .ok();
+                            break;
+                        }
+                        let delay = self.account.auth_retry();
+                        warn!(
+                            "login rejected: {:#}; trying again after {}s ...",
+                            e,
+                            delay.as_secs()
+                        );
+                        self.report_failure(
+                            kind,
+                            &format!("{:#}; trying again after {}s ...", e, delay.as_secs()),
+                        )
+                        .await
+                        .ok();
+                        error_reported = true;
+                        tokio::select! {
+                            _ = self.alive_while(sleep(delay)) => {}
+                            _ = shutdown.cancelled() => break,
+                        }
+                        continue;
+                    }
+                    warn!(
+                        kind = kind.map(FailureKind::tag),
+                        "connection failed: {:#}; trying to reconnect after {wait}s ...", e
                     );
-                    if wait >= 256 {
-                        self.report_error(&format!(
-                            "connection failed: {}; trying to reconnect after {wait}s ...",
-                            e
-                        ))
+                    if failing_since.elapsed() >= self.account.alert_after() {
+                        self.report_failure(
+                            kind,
+                            &format!(
+                                "connection failed: {:#}; trying to reconnect after {wait}s ...",
+                                e
+                            ),
+                        )
                         .await
                         .ok();
+                        error_reported = true;
+                    }
+                    tokio::select! {
+                        _ = self.alive_while(sleep(self.account.backoff_delay(wait))) => {}
+                        _ = shutdown.cancelled() => break,
                     }
-                    sleep(Duration::from_secs(wait)).await;
-                    wait *= 2;
+                    wait = std::cmp::min(wait * 2, self.account.max_backoff_secs.get());
                 }
             }
         }
     }
 }
 
+#[async_trait]
+impl MarkSeen for UnseenMail {
+    /// Uses a separate session, the main one is usually busy idling.
+    async fn mark_seen(&self, folder: &str, uid: u32) -> Result<()> {
+        let mut session = self.new_session().await?;
+        let result = async {
+            session.select(folder).await?;
+            let updates = session
+                .uid_store(uid.to_string(), "+FLAGS.SILENT (\\Seen)")
+                .await?
+                .collect::<Vec<_>>()
+                .await;
+            for update in updates {
+                update?;
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        self.logout(session).await;
+        result
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
-    let config_path = args.config;
-    let mut buf = String::new();
-    fs::File::open(config_path)
-        .unwrap()
-        .read_to_string(&mut buf)
-        .unwrap();
-    let config: Config = toml::from_str(&buf).unwrap();
-    let accounts = config.accounts;
-    let tasks = accounts
+    if args.print_config_template {
+        print!("{}", template::config_template());
+        return Ok(());
+    }
+    let mut config = Config::load(&args.config, args.config_dir.as_deref())?;
+    let logging = Logging::init(&config, args.log_format)?;
+    only_accounts(&mut config, &args.accounts)?;
+    skip_disabled(&mut config);
+    config.validate()?;
+    if args.validate {
+        return validate_accounts(&config).await;
+    }
+    if args.test_notify {
+        return test_notify(&config).await;
+    }
+    if let Some(name) = &args.list_folders {
+        return list_folders(config, name).await;
+    }
+    let state_file = config.state_file.clone();
+    let log_file = config.log_file.clone();
+    let state = Arc::new(StateStore::load(state_file.clone()));
+    let metrics = Arc::new(Metrics::default());
+    let shutdown_timeout = config.shutdown_timeout();
+    let max_concurrent_connections = config.max_concurrent_connections;
+    let startup_stagger_ms = config.startup_stagger_ms;
+    let connections = max_concurrent_connections.map(|max| Arc::new(Semaphore::new(max.get())));
+    let shutdown = CancellationToken::new();
+    task::spawn(wait_for_shutdown_signal(shutdown.clone()));
+    if args.once {
+        if state_file.is_none() {
+            warn!("without `state_file` every `--once` run may notify the same mail again");
+        }
+        let unseen_mails = config
+            .accounts
+            .into_iter()
+            .map(|account| {
+                UnseenMail::new(
+                    account,
+                    state.clone(),
+                    metrics.clone(),
+                    None,
+                    connections.clone(),
+                    args.dry_run,
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let tasks = unseen_mails
+            .into_iter()
+            .enumerate()
+            .map(|(i, unseen_mail)| {
+                let span = info_span!("account", name = %unseen_mail.account.name);
+                async move {
+                    sleep(Config::startup_delay(startup_stagger_ms, i)).await;
+                    let result = unseen_mail.run_once().await;
+                    if let Err(e) = &result {
+                        error!("check failed: {:#}", e);
+                    }
+                    result
+                }
+                .instrument(span)
+            });
+        let results = join_all(tasks).await;
+        let failed = results.iter().filter(|result| result.is_err()).count();
+        if failed > 0 {
+            bail!("{} of {} accounts failed", failed, results.len());
+        }
+        return Ok(());
+    }
+    if let Some(metrics_config) = &config.metrics {
+        let listener = TcpListener::bind(metrics_config.bind)
+            .await
+            .with_context(|| format!("failed to bind metrics to {}", metrics_config.bind))?;
+        task::spawn(metrics::serve(listener, metrics.clone()));
+    }
+    let actions = match config.actions {
+        Some(actions_config) => {
+            let listener = TcpListener::bind(actions_config.bind)
+                .await
+                .with_context(|| format!("failed to bind actions to {}", actions_config.bind))?;
+            let actions = Arc::new(Actions::new(actions_config));
+            task::spawn(actions::serve(listener, actions.clone()));
+            Some(actions)
+        }
+        None => None,
+    };
+    let metrics_config = config.metrics;
+    let sd_notify = SdNotify::from_env().map(Arc::new);
+    let watchdog_interval = sd_notify
+        .as_ref()
+        .and_then(|_| SdNotify::watchdog_interval());
+    let liveness = watchdog_interval.map(Liveness::new);
+    let mut supervisor = Supervisor {
+        state,
+        metrics,
+        actions: actions.clone(),
+        connections,
+        dry_run: args.dry_run,
+        shutdown: shutdown.clone(),
+        liveness: liveness.clone(),
+        tasks: HashMap::new(),
+    };
+    let mut first_connections = Vec::new();
+    for (i, account) in config.accounts.into_iter().enumerate() {
+        let first_connection = config.startup_notification.as_ref().map(|_| {
+            let (sender, receiver) = oneshot::channel();
+            first_connections.push((account.name.clone(), receiver));
+            sender
+        });
+        supervisor.start(
+            account,
+            Config::startup_delay(startup_stagger_ms, i),
+            first_connection,
+        )?;
+    }
+    if let Some(startup_notification) = config.startup_notification {
+        task::spawn(startup::notify(
+            startup_notification,
+            first_connections,
+            args.dry_run,
+            shutdown.clone(),
+        ));
+    }
+    if let Some(sd_notify) = &sd_notify {
+        sd_notify.notify("READY=1");
+    }
+    // its own task, so a reload waiting for accounts to log out doesn't starve the watchdog
+    if let (Some(sd_notify), Some(liveness), Some(interval)) =
+        (&sd_notify, liveness, watchdog_interval)
+    {
+        task::spawn(systemd::watchdog(sd_notify.clone(), liveness, interval));
+    }
+    let mut sighup = signal(SignalKind::hangup()).context("failed to install SIGHUP handler")?;
+    loop {
+        tokio::select! {
+            _ = sighup.recv() => {}
+            _ = shutdown.cancelled() => break,
+        }
+        if args.config.iter().any(|path| config::is_stdin(path)) {
+            warn!("received SIGHUP, but a config read from stdin can't be reloaded");
+            continue;
+        }
+        info!("received SIGHUP, reloading config");
+        let config =
+            match Config::load(&args.config, args.config_dir.as_deref()).and_then(|mut config| {
+                only_accounts(&mut config, &args.accounts)?;
+                skip_disabled(&mut config);
+                config.validate()?;
+                Ok(config)
+            }) {
+                Ok(config) => config,
+                Err(e) => {
+                    error!("keeping the running config: {:#}", e);
+                    continue;
+                }
+            };
+        if config.state_file != state_file
+            || config.log_file != log_file
+            || config.max_concurrent_connections != max_concurrent_connections
+            || config.shutdown_timeout() != shutdown_timeout
+            || config.startup_stagger_ms != startup_stagger_ms
+            || config.metrics != metrics_config
+            || config.actions.as_ref() != actions.as_ref().map(|actions| actions.config())
+        {
+            warn!(
+                "changes to `state_file`, `log_file`, `max_concurrent_connections`, `shutdown_timeout_secs`, `startup_stagger_ms`, `[metrics]` and `[actions]` only take effect after a restart"
+            );
+        }
+        logging.reload(&config.accounts);
+        supervisor.apply(config.accounts).await;
+    }
+    if let Some(sd_notify) = &sd_notify {
+        sd_notify.notify("STOPPING=1");
+    }
+    // accounts finish what they are sending and log out, but a hung server can't delay the exit
+    // forever
+    if tokio::time::timeout(shutdown_timeout, supervisor.wait())
+        .await
+        .is_err()
+    {
+        warn!(
+            "accounts didn't shut down within {}s, exiting anyway",
+            shutdown_timeout.as_secs()
+        );
+    }
+    Ok(())
+}
+
+/// An account task, kept so a reload can stop or replace it.
+struct AccountTask {
+    raw: toml::Table,
+    cancel: CancellationToken,
+    handle: task::JoinHandle<()>,
+}
+
+/// Runs one task per account and starts, stops or restarts them when the config is reloaded.
+struct Supervisor {
+    state: Arc<StateStore>,
+    metrics: Arc<Metrics>,
+    actions: Option<Arc<Actions>>,
+    connections: Option<Arc<Semaphore>>,
+    dry_run: bool,
+    /// Parent of every account's token, so a shutdown reaches all of them.
+    shutdown: CancellationToken,
+    /// Beaten by every account, with a systemd watchdog.
+    liveness: Option<Liveness>,
+    tasks: HashMap<String, AccountTask>,
+}
+
+impl Supervisor {
+    /// Starts the account's task, which waits `delay` before it first connects and then tells
+    /// `first_connection` how that went.
+    fn start(
+        &mut self,
+        account: Account,
+        delay: Duration,
+        first_connection: Option<oneshot::Sender<Option<String>>>,
+    ) -> Result<()> {
+        let name = account.name.clone();
+        let raw = account.raw.clone();
+        let unseen_mail = Arc::new(
+            UnseenMail::new(
+                account,
+                self.state.clone(),
+                self.metrics.clone(),
+                self.actions.clone(),
+                self.connections.clone(),
+                self.dry_run,
+            )?
+            .report_first_connection(first_connection)
+            .report_liveness(self.liveness.clone()),
+        );
+        if let Some(actions) = &self.actions {
+            actions.register(&name, unseen_mail.clone());
+        }
+        let cancel = self.shutdown.child_token();
+        let span = info_span!("account", name = %name);
+        let handle = task::spawn({
+            let cancel = cancel.clone();
+            let liveness = self.liveness.clone();
+            let name = name.clone();
+            async move {
+                if !delay.is_zero() {
+                    debug!("waiting {}ms before connecting", delay.as_millis());
+                }
+                tokio::select! {
+                    _ = unseen_mail.alive_while(sleep(delay)) => {
+                        unseen_mail.supervise(cancel).await;
+                    }
+                    _ = cancel.cancelled() => {}
+                }
+                // a stopped account can't be stuck
+                if let Some(liveness) = liveness {
+                    liveness.forget(&name);
+                }
+            }
+            .instrument(span)
+        });
+        self.tasks.insert(
+            name,
+            AccountTask {
+                raw,
+                cancel,
+                handle,
+            },
+        );
+        Ok(())
+    }
+
+    /// Cancels the account's task and waits until it has logged out.
+    async fn stop(&mut self, name: &str) {
+        if let Some(actions) = &self.actions {
+            actions.unregister(name);
+        }
+        if let Some(task) = self.tasks.remove(name) {
+            task.cancel.cancel();
+            if let Err(e) = task.handle.await {
+                error!(account = name, "account task failed: {}", e);
+            }
+        }
+    }
+
+    /// Brings the running tasks in line with `accounts`. Accounts whose settings are unchanged
+    /// keep their session.
+    async fn apply(&mut self, accounts: Vec<Account>) {
+        let names: HashSet<&str> = accounts
+            .iter()
+            .map(|account| account.name.as_str())
+            .collect();
+        let removed: Vec<String> = self
+            .tasks
+            .keys()
+            .filter(|name| !names.contains(name.as_str()))
+            .cloned()
+            .collect();
+        for name in removed {
+            info!(account = %name, "account removed, stopping");
+            self.stop(&name).await;
+        }
+        for account in accounts {
+            match self.tasks.get(&account.name) {
+                Some(task) if task.raw == account.raw => continue,
+                Some(_) => {
+                    info!(account = %account.name, "account changed, restarting");
+                    self.stop(&account.name).await;
+                }
+                None => info!(account = %account.name, "account added, starting"),
+            }
+            let name = account.name.clone();
+            if let Err(e) = self.start(account, Duration::ZERO, None) {
+                error!(account = %name, "failed to start: {:#}", e);
+            }
+        }
+    }
+
+    /// Waits for every task, which only return once `shutdown` is cancelled.
+    async fn wait(self) {
+        join_all(self.tasks.into_values().map(|task| task.handle)).await;
+    }
+}
+
+/// Keeps only the accounts named with `--account`, or all of them without it.
+fn only_accounts(config: &mut Config, names: &[String]) -> Result<()> {
+    if names.is_empty() {
+        return Ok(());
+    }
+    let unknown: Vec<_> = names
+        .iter()
+        .filter(|name| !config.accounts.iter().any(|account| &account.name == *name))
+        .map(|name| format!("[{}]", name))
+        .collect();
+    if !unknown.is_empty() {
+        bail!("no account named {} in the config", unknown.join(", "));
+    }
+    config
+        .accounts
+        .retain(|account| names.contains(&account.name));
+    Ok(())
+}
+
+/// Drops the accounts with `enabled = false`, they are neither validated nor watched.
+fn skip_disabled(config: &mut Config) {
+    config.accounts.retain(|account| {
+        if !account.enabled {
+            info!("account [{}] is disabled, skipping it", account.name);
+        }
+        account.enabled
+    });
+}
+
+/// Resolves the credentials of every account and prints one line per account, without opening
+/// any connection. Secrets are only checked for being available, never printed.
+async fn validate_accounts(config: &Config) -> Result<()> {
+    let mut failed = 0;
+    for account in &config.accounts {
+        let secret = match &account.auth {
+            Auth::Password => match account.password_source() {
+                Ok(source) => source.resolve().await.map(drop),
+                Err(e) => Err(e),
+            },
+            Auth::Xoauth2 { .. } => account.auth.access_token().await.map(drop),
+        };
+        let result = secret.and_then(|()| notifier::from_account(account).map(drop));
+        let summary = format!(
+            "[{}] {}@{}:{}, folders: {}",
+            account.name,
+            account.username,
+            account.server,
+            account.port,
+            account
+                .folders
+                .iter()
+                .map(|folder| folder.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        match result {
+            Ok(()) => println!("{}: ok", summary),
+            Err(e) => {
+                failed += 1;
+                println!("{}: {:#}", summary, e);
+            }
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "{} of {} accounts are invalid",
+            failed,
+            config.accounts.len()
+        );
+    }
+    Ok(())
+}
+
+/// Sends one notification through the notifier of every account, printing a line per account.
+async fn test_notify(config: &Config) -> Result<()> {
+    let mut failed = 0;
+    for account in &config.accounts {
+        let notification = Notification::new(
+            format!("@{} test notification", account.name),
+            "test notification from unseenmail",
+            account.ntfy_priority,
+        );
+        let result = match notifier::from_account(account) {
+            Ok(notifier) => notifier.notify(&notification).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => println!("[{}]: sent", account.name),
+            Err(e) => {
+                failed += 1;
+                println!("[{}]: {:#}", account.name, e);
+            }
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "{} of {} test notifications failed",
+            failed,
+            config.accounts.len()
+        );
+    }
+    Ok(())
+}
+
+/// Prints the folders of account `name`.
+async fn list_folders(config: Config, name: &str) -> Result<()> {
+    let Some(account) = config
+        .accounts
         .into_iter()
-        .map(|account| UnseenMail { account }.run());
-    join_all(tasks).await;
+        .find(|account| account.name == name)
+    else {
+        bail!("no enabled account named [{}]", name);
+    };
+    let unseen_mail = UnseenMail::new(
+        account,
+        Arc::new(StateStore::load(None)),
+        Arc::new(Metrics::default()),
+        None,
+        None,
+        false,
+    )?;
+    for line in unseen_mail.list_folders().await? {
+        println!("{}", line);
+    }
     Ok(())
 }
+
+/// Cancels `shutdown` on the first SIGINT or SIGTERM so every account can log out cleanly.
+async fn wait_for_shutdown_signal(shutdown: CancellationToken) {
+    let mut sigterm = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("received SIGINT, shutting down"),
+        _ = sigterm.recv() => info!("received SIGTERM, shutting down"),
+    }
+    shutdown.cancel();
+}
@@ -0,0 +1,152 @@
+use crate::http;
+use serde::Deserialize;
+use std::{collections::BTreeMap, fmt::Write, net::SocketAddr, sync::Arc, sync::Mutex};
+use tokio::{
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+    task,
+};
+use tracing::{debug, info, warn, Instrument};
+
+/// The `[metrics]` table, the endpoint is only served when it is present.
+#[derive(Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address to serve `/metrics` on, e.g. `127.0.0.1:9898`.
+    pub bind: SocketAddr,
+}
+
+#[derive(Default)]
+struct AccountMetrics {
+    new_messages: u64,
+    notifications_sent: u64,
+    reconnects: u64,
+    connected: bool,
+}
+
+/// Counters shared by every account task, rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    accounts: Mutex<BTreeMap<String, AccountMetrics>>,
+}
+
+impl Metrics {
+    fn update(&self, account: &str, f: impl FnOnce(&mut AccountMetrics)) {
+        let mut accounts = self.accounts.lock().unwrap();
+        f(accounts.entry(account.to_string()).or_default());
+    }
+
+    pub fn new_messages(&self, account: &str, count: usize) {
+        self.update(account, |m| m.new_messages += count as u64);
+    }
+
+    pub fn notification_sent(&self, account: &str) {
+        self.update(account, |m| m.notifications_sent += 1);
+    }
+
+    pub fn reconnect(&self, account: &str) {
+        self.update(account, |m| m.reconnects += 1);
+    }
+
+    pub fn set_connected(&self, account: &str, connected: bool) {
+        self.update(account, |m| m.connected = connected);
+    }
+
+    fn render(&self) -> String {
+        let accounts = self.accounts.lock().unwrap();
+        let mut out = String::new();
+        let mut family = |name: &str, kind: &str, help: &str, value: fn(&AccountMetrics) -> u64| {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+            for (account, metrics) in accounts.iter() {
+                writeln!(
+                    out,
+                    "{}{{account=\"{}\"}} {}",
+                    name,
+                    escape_label(account),
+                    value(metrics)
+                )
+                .unwrap();
+            }
+        };
+        family(
+            "unseenmail_new_messages_total",
+            "counter",
+            "New messages that passed the filters.",
+            |m| m.new_messages,
+        );
+        family(
+            "unseenmail_notifications_sent_total",
+            "counter",
+            "Notifications delivered to the notifier.",
+            |m| m.notifications_sent,
+        );
+        family(
+            "unseenmail_reconnects_total",
+            "counter",
+            "Connection attempts after the first one.",
+            |m| m.reconnects,
+        );
+        family(
+            "unseenmail_connected",
+            "gauge",
+            "Whether the account currently has an IMAP session.",
+            |m| m.connected as u64,
+        );
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Answers `GET /metrics` on `listener` until the process exits.
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    if let Ok(addr) = listener.local_addr() {
+        info!(%addr, "serving metrics");
+    }
+    let connections = Arc::new(Semaphore::new(http::MAX_CONNECTIONS));
+    loop {
+        // never closed
+        let Ok(permit) = connections.clone().acquire_owned().await else {
+            return;
+        };
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let metrics = metrics.clone();
+                task::spawn(
+                    async move {
+                        if let Err(e) = respond(stream, &metrics).await {
+                            debug!("metrics request failed: {}", e);
+                        }
+                        drop(permit);
+                    }
+                    .instrument(tracing::debug_span!("metrics", %peer)),
+                );
+            }
+            Err(e) => warn!("failed to accept metrics connection: {}", e),
+        }
+    }
+}
+
+async fn respond(stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let request = http::read_request(&mut stream).await?;
+    if request.method == "GET" && request.target == "/metrics" {
+        let body = metrics.render();
+        http::write_response(
+            &mut stream,
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            &body,
+        )
+        .await
+    } else {
+        http::write_response(&mut stream, "404 Not Found", "text/plain", "not found\n").await
+    }
+}
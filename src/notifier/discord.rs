@@ -0,0 +1,132 @@
+use super::{Notification, Notifier, Priority};
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Serialize;
+
+/// Discord rejects messages whose `content` is longer than this.
+const CONTENT_LIMIT: usize = 2000;
+const TITLE_LIMIT: usize = 256;
+const DESCRIPTION_LIMIT: usize = 4096;
+const FIELD_LIMIT: usize = 1024;
+
+/// Posts notifications to a Discord channel through an incoming webhook.
+///
+/// See <https://discord.com/developers/docs/resources/webhook#execute-webhook>.
+pub struct DiscordNotifier {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[derive(Serialize)]
+struct Message {
+    content: String,
+    embeds: [Embed; 1],
+}
+
+#[derive(Serialize)]
+struct Embed {
+    title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    color: u32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<Field>,
+}
+
+#[derive(Serialize)]
+struct Field {
+    name: &'static str,
+    value: String,
+    inline: bool,
+}
+
+/// The embed's side bar color, red for anything above the default priority.
+fn color(priority: Priority) -> u32 {
+    match priority {
+        Priority::Min | Priority::Low => 0x95a5a6,
+        Priority::Default => 0x5865f2,
+        Priority::High | Priority::Max => 0xed4245,
+    }
+}
+
+/// Cuts `s` to at most `limit` characters, marking the cut with an ellipsis.
+fn truncate(s: &str, limit: usize) -> String {
+    match s.char_indices().nth(limit - 1) {
+        Some((end, _)) if s[end..].chars().nth(1).is_some() => format!("{}…", &s[..end]),
+        _ => s.to_string(),
+    }
+}
+
+impl DiscordNotifier {
+    pub fn new(url: &str) -> Self {
+        DiscordNotifier {
+            client: reqwest::Client::new(),
+            url: url.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for DiscordNotifier {
+    async fn notify(&self, notification: &Notification) -> Result<()> {
+        // Discord refuses the whole message for links it cannot open, such as the `k9mail://`
+        // URLs mail apps use
+        let url = notification
+            .click
+            .clone()
+            .filter(|url| url.starts_with("https://") || url.starts_with("http://"));
+        let embed = match &notification.mail {
+            Some(mail) => {
+                let mut fields = vec![Field {
+                    name: "From",
+                    value: truncate(&mail.from, FIELD_LIMIT),
+                    inline: true,
+                }];
+                if let Some(to) = &mail.to {
+                    fields.push(Field {
+                        name: "To",
+                        value: truncate(to, FIELD_LIMIT),
+                        inline: true,
+                    });
+                }
+                if let Some(date) = &mail.date {
+                    fields.push(Field {
+                        name: "Date",
+                        value: truncate(date, FIELD_LIMIT),
+                        inline: true,
+                    });
+                }
+                Embed {
+                    title: truncate(&mail.subject, TITLE_LIMIT),
+                    description: mail
+                        .preview
+                        .as_deref()
+                        .map(|preview| truncate(preview, DESCRIPTION_LIMIT)),
+                    url,
+                    color: color(notification.priority),
+                    fields,
+                }
+            }
+            None => Embed {
+                title: truncate(&notification.title, TITLE_LIMIT),
+                description: Some(truncate(&notification.message, DESCRIPTION_LIMIT)),
+                url,
+                color: color(notification.priority),
+                fields: Vec::new(),
+            },
+        };
+        let message = Message {
+            content: truncate(&notification.title, CONTENT_LIMIT),
+            embeds: [embed],
+        };
+        self.client
+            .post(&self.url)
+            .json(&message)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+use super::{Notification, Notifier, Priority};
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Serialize;
+
+/// Pushes notifications to a Gotify server with its `POST /message` API.
+///
+/// See <https://gotify.net/docs/pushmsg>.
+pub struct GotifyNotifier {
+    client: reqwest::Client,
+    url: String,
+    app_token: String,
+}
+
+#[derive(Serialize)]
+struct Message<'a> {
+    title: &'a str,
+    message: &'a str,
+    priority: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extras: Option<Extras<'a>>,
+}
+
+/// Carries the click URL, which the Gotify Android app opens when the notification is tapped.
+#[derive(Serialize)]
+struct Extras<'a> {
+    #[serde(rename = "client::notification")]
+    notification: ClientNotification<'a>,
+}
+
+#[derive(Serialize)]
+struct ClientNotification<'a> {
+    click: Click<'a>,
+}
+
+#[derive(Serialize)]
+struct Click<'a> {
+    url: &'a str,
+}
+
+/// Maps a priority onto Gotify's 0 to 10 scale. The Android app only shows the notification in
+/// the status bar up to 3, plays a sound from 4 and pops it up from 8.
+fn gotify_priority(priority: Priority) -> u8 {
+    match priority {
+        Priority::Min => 1,
+        Priority::Low => 3,
+        Priority::Default => 5,
+        Priority::High => 8,
+        Priority::Max => 10,
+    }
+}
+
+impl GotifyNotifier {
+    pub fn new(url: &str, app_token: &str) -> Self {
+        GotifyNotifier {
+            client: reqwest::Client::new(),
+            url: format!("{}/message", url.trim_end_matches('/')),
+            app_token: app_token.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for GotifyNotifier {
+    async fn notify(&self, notification: &Notification) -> Result<()> {
+        let message = Message {
+            title: &notification.title,
+            message: &notification.message,
+            priority: gotify_priority(notification.priority),
+            extras: notification.click.as_deref().map(|url| Extras {
+                notification: ClientNotification {
+                    click: Click { url },
+                },
+            }),
+        };
+        self.client
+            .post(&self.url)
+            .query(&[("token", &self.app_token)])
+            .json(&message)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
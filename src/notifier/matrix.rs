@@ -0,0 +1,96 @@
+use super::{escape_html, Notification, Notifier, Priority};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Sends notifications to a Matrix room with the client-server API.
+///
+/// See <https://spec.matrix.org/latest/client-server-api/#put_matrixclientv3roomsroomidsendeventtypetxnid>.
+pub struct MatrixNotifier {
+    client: reqwest::Client,
+    url: String,
+    access_token: String,
+    /// Start of every transaction id, so ids stay unique across restarts with the same token.
+    txn_prefix: u128,
+    txn_counter: AtomicU64,
+}
+
+#[derive(Serialize)]
+struct RoomMessage {
+    msgtype: &'static str,
+    body: String,
+    format: &'static str,
+    formatted_body: String,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    errcode: String,
+    #[serde(default)]
+    error: String,
+}
+
+impl MatrixNotifier {
+    pub fn new(homeserver_url: &str, access_token: &str, room_id: &str) -> Self {
+        MatrixNotifier {
+            client: reqwest::Client::new(),
+            url: format!(
+                "{}/_matrix/client/v3/rooms/{}/send/m.room.message",
+                homeserver_url.trim_end_matches('/'),
+                utf8_percent_encode(room_id, NON_ALPHANUMERIC)
+            ),
+            access_token: access_token.to_string(),
+            txn_prefix: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            txn_counter: AtomicU64::new(0),
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for MatrixNotifier {
+    async fn notify(&self, notification: &Notification) -> Result<()> {
+        let txn_id = format!(
+            "unseenmail-{}-{}",
+            self.txn_prefix,
+            self.txn_counter.fetch_add(1, Ordering::Relaxed)
+        );
+        let message = RoomMessage {
+            // clients usually stay quiet for notices, which suits low priority mail
+            msgtype: if notification.priority <= Priority::Low {
+                "m.notice"
+            } else {
+                "m.text"
+            },
+            body: format!("{}\n{}", notification.title, notification.message),
+            format: "org.matrix.custom.html",
+            formatted_body: format!(
+                "<b>{}</b><br>{}",
+                escape_html(&notification.title),
+                escape_html(&notification.message).replace('\n', "<br>")
+            ),
+        };
+        let response = self
+            .client
+            .put(format!("{}/{}", self.url, txn_id))
+            .bearer_auth(&self.access_token)
+            .json(&message)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            match response.json::<ErrorResponse>().await {
+                Ok(e) => bail!("matrix error {}: {} {}", status, e.errcode, e.error),
+                Err(_) => bail!("matrix error {}", status),
+            }
+        }
+        Ok(())
+    }
+}
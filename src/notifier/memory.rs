@@ -0,0 +1,39 @@
+use super::{Notification, Notifier};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+/// Keeps every notification instead of sending it, so tests can check what would have been sent.
+/// Clones share the same list.
+#[derive(Clone, Default)]
+pub struct MemoryNotifier {
+    sent: Arc<Mutex<Vec<Notification>>>,
+    failures: Arc<AtomicUsize>,
+}
+
+impl MemoryNotifier {
+    pub fn sent(&self) -> Vec<Notification> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    /// Fails the next `count` notifications instead of keeping them.
+    pub fn fail_next(&self, count: usize) {
+        self.failures.store(count, Ordering::SeqCst);
+    }
+}
+
+#[async_trait]
+impl Notifier for MemoryNotifier {
+    async fn notify(&self, notification: &Notification) -> Result<()> {
+        let failures = self.failures.load(Ordering::SeqCst);
+        if failures > 0 {
+            self.failures.store(failures - 1, Ordering::SeqCst);
+            bail!("failing on purpose");
+        }
+        self.sent.lock().unwrap().push(notification.clone());
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+mod discord;
+mod gotify;
+mod matrix;
+#[cfg(test)]
+mod memory;
+mod ntfy;
+mod telegram;
+mod webhook;
+
+use crate::config::{Account, NotifierConfig};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+pub use self::discord::DiscordNotifier;
+pub use self::gotify::GotifyNotifier;
+pub use self::matrix::MatrixNotifier;
+#[cfg(test)]
+pub use self::memory::MemoryNotifier;
+pub use self::ntfy::{NtfyAuth, NtfyNotifier};
+pub use self::telegram::{ChatId, TelegramNotifier};
+pub use self::webhook::WebhookNotifier;
+
+/// Notification priority, written as an ntfy priority name or its number from 1 to 5.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Min,
+    Low,
+    #[default]
+    Default,
+    High,
+    Max,
+}
+
+impl<'de> Deserialize<'de> for Priority {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Name(String),
+            Level(i64),
+        }
+        let priority = match Raw::deserialize(deserializer)? {
+            Raw::Name(name) => match name.as_str() {
+                "min" => Some(Priority::Min),
+                "low" => Some(Priority::Low),
+                "default" => Some(Priority::Default),
+                "high" => Some(Priority::High),
+                "max" | "urgent" => Some(Priority::Max),
+                _ => None,
+            }
+            .ok_or(name),
+            Raw::Level(level) => match level {
+                1 => Some(Priority::Min),
+                2 => Some(Priority::Low),
+                3 => Some(Priority::Default),
+                4 => Some(Priority::High),
+                5 => Some(Priority::Max),
+                _ => None,
+            }
+            .ok_or(level.to_string()),
+        };
+        priority.map_err(|value| {
+            de::Error::custom(format!(
+                "invalid priority `{}`, expected one of min, low, default, high, max or 1-5",
+                value
+            ))
+        })
+    }
+}
+
+/// Written as its level, which `Deserialize` reads back.
+impl Serialize for Priority {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.level())
+    }
+}
+
+impl Priority {
+    /// The ntfy-style level from 1 (min) to 5 (max).
+    pub fn level(self) -> u8 {
+        match self {
+            Priority::Min => 1,
+            Priority::Low => 2,
+            Priority::Default => 3,
+            Priority::High => 4,
+            Priority::Max => 5,
+        }
+    }
+}
+
+/// A single message to deliver, independent of the backend.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Notification {
+    pub title: String,
+    pub message: String,
+    pub priority: Priority,
+    /// ntfy tags (usually emoji shortcodes), backends without tags ignore them.
+    pub tags: Vec<String>,
+    /// ntfy topic to publish to instead of the account's, other backends ignore it.
+    pub topic: Option<String>,
+    /// URL to open when the notification is clicked, if the backend supports it.
+    pub click: Option<String>,
+    /// Buttons on the notification, only ntfy shows them.
+    pub actions: Vec<Action>,
+    /// The message a new mail notification is about, for backends that lay out the sender and
+    /// subject themselves instead of showing `message`.
+    pub mail: Option<MailDetails>,
+}
+
+/// The parts of a single new message that make up a new mail notification.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MailDetails {
+    pub from: String,
+    /// With `recipient_in_notification`, who the mail was sent to.
+    #[serde(default)]
+    pub to: Option<String>,
+    pub subject: String,
+    pub date: Option<String>,
+    pub preview: Option<String>,
+}
+
+/// A notification button that sends a POST request to `url` when tapped.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Action {
+    pub label: String,
+    pub url: String,
+    /// Carries the `[actions]` token, so it is never written to the notification queue.
+    #[serde(skip)]
+    pub headers: Vec<(String, String)>,
+}
+
+impl Notification {
+    pub fn new(title: impl Into<String>, message: impl Into<String>, priority: Priority) -> Self {
+        Notification {
+            title: title.into(),
+            message: message.into(),
+            priority,
+            tags: Vec::new(),
+            topic: None,
+            click: None,
+            actions: Vec::new(),
+            mail: None,
+        }
+    }
+
+    /// Adds a tag unless the notification already has it.
+    pub fn tag(mut self, tag: impl Into<String>) -> Self {
+        let tag = tag.into();
+        if !self.tags.contains(&tag) {
+            self.tags.push(tag);
+        }
+        self
+    }
+
+    pub fn topic(mut self, topic: Option<String>) -> Self {
+        self.topic = topic;
+        self
+    }
+
+    pub fn click(mut self, click: Option<String>) -> Self {
+        self.click = click;
+        self
+    }
+
+    pub fn action(mut self, action: Action) -> Self {
+        self.actions.push(action);
+        self
+    }
+
+    pub fn mail(mut self, mail: MailDetails) -> Self {
+        self.mail = Some(mail);
+        self
+    }
+}
+
+/// A notification backend.
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    async fn notify(&self, notification: &Notification) -> Result<()>;
+}
+
+/// Builds the notifier selected by `account.notifier`.
+pub fn from_account(account: &Account) -> Result<Box<dyn Notifier>> {
+    match &account.notifier {
+        NotifierConfig::Ntfy => {
+            let url = account
+                .ntfy_url
+                .as_deref()
+                .context("`ntfy_url` is missing")?;
+            let topics = account
+                .ntfy_topic
+                .as_deref()
+                .context("`ntfy_topic` is missing")?;
+            Ok(Box::new(
+                NtfyNotifier::new(
+                    url,
+                    topics,
+                    account.ntfy_auth()?,
+                    account.ntfy_icon.as_deref(),
+                )?
+                .cache(account.ntfy_cache.unwrap_or(true))
+                .firebase(account.ntfy_firebase.unwrap_or(true)),
+            ))
+        }
+        NotifierConfig::Webhook { url, headers } => {
+            Ok(Box::new(WebhookNotifier::new(url, headers, &account.name)?))
+        }
+        NotifierConfig::Telegram { bot_token, chat_id } => {
+            Ok(Box::new(TelegramNotifier::new(bot_token, chat_id)))
+        }
+        NotifierConfig::Discord { url } => Ok(Box::new(DiscordNotifier::new(url))),
+        NotifierConfig::Matrix {
+            homeserver_url,
+            access_token,
+            room_id,
+        } => Ok(Box::new(MatrixNotifier::new(
+            homeserver_url,
+            access_token,
+            room_id,
+        ))),
+        NotifierConfig::Gotify { url, app_token } => {
+            Ok(Box::new(GotifyNotifier::new(url, app_token)))
+        }
+    }
+}
+
+/// Escapes text for the HTML subset Telegram and Matrix clients render.
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,169 @@
+use super::{Action, Notification, Notifier};
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::future::join_all;
+use serde::Serialize;
+use std::collections::HashMap;
+use tracing::warn;
+
+/// Credentials for a protected ntfy server.
+pub enum NtfyAuth {
+    /// An access token, sent as a bearer token.
+    Token(String),
+    Basic {
+        username: String,
+        password: String,
+    },
+}
+
+/// Publishes to one or more topics with ntfy's JSON publishing API.
+///
+/// See <https://docs.ntfy.sh/publish/#publish-as-json>.
+pub struct NtfyNotifier {
+    client: reqwest::Client,
+    url: String,
+    topics: Vec<String>,
+    auth: Option<NtfyAuth>,
+    icon: Option<String>,
+    /// Sent as `Cache: no` when false, the server then only delivers to connected clients.
+    cache: bool,
+    /// Sent as `Firebase: no` when false, ntfy.sh then skips Firebase Cloud Messaging.
+    firebase: bool,
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    topic: &'a str,
+    title: &'a str,
+    message: &'a str,
+    priority: u8,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    tags: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    click: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    actions: Vec<HttpAction<'a>>,
+}
+
+/// An ntfy `http` action button.
+#[derive(Serialize)]
+struct HttpAction<'a> {
+    action: &'static str,
+    label: &'a str,
+    url: &'a str,
+    method: &'static str,
+    headers: HashMap<&'a str, &'a str>,
+    /// Dismiss the notification once the request succeeded.
+    clear: bool,
+}
+
+impl<'a> From<&'a Action> for HttpAction<'a> {
+    fn from(action: &'a Action) -> Self {
+        HttpAction {
+            action: "http",
+            label: &action.label,
+            url: &action.url,
+            method: "POST",
+            headers: action
+                .headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect(),
+            clear: true,
+        }
+    }
+}
+
+impl NtfyNotifier {
+    pub fn new(
+        url: &str,
+        topics: &[String],
+        auth: Option<NtfyAuth>,
+        icon: Option<&str>,
+    ) -> Result<Self> {
+        Ok(NtfyNotifier {
+            client: reqwest::Client::builder().build()?,
+            url: url.to_string(),
+            topics: topics.to_vec(),
+            auth,
+            icon: icon.map(str::to_string),
+            cache: true,
+            firebase: true,
+        })
+    }
+
+    pub fn cache(mut self, cache: bool) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub fn firebase(mut self, firebase: bool) -> Self {
+        self.firebase = firebase;
+        self
+    }
+
+    async fn publish(&self, notification: &Notification, topic: &str) -> Result<()> {
+        let payload = Payload {
+            topic,
+            title: &notification.title,
+            message: &notification.message,
+            priority: notification.priority.level(),
+            tags: &notification.tags,
+            click: notification.click.as_deref(),
+            icon: self.icon.as_deref(),
+            actions: notification.actions.iter().map(HttpAction::from).collect(),
+        };
+        let mut request = self.client.post(&self.url).json(&payload);
+        match &self.auth {
+            Some(NtfyAuth::Token(token)) => request = request.bearer_auth(token),
+            Some(NtfyAuth::Basic { username, password }) => {
+                request = request.basic_auth(username, Some(password))
+            }
+            None => {}
+        }
+        // these have no field in the JSON body
+        if !self.cache {
+            request = request.header("Cache", "no");
+        }
+        if !self.firebase {
+            request = request.header("Firebase", "no");
+        }
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Notifier for NtfyNotifier {
+    /// Publishes to the folder's topic if the notification has one, otherwise to every topic of
+    /// the account. A topic that fails is only logged as long as another one got the
+    /// notification.
+    async fn notify(&self, notification: &Notification) -> Result<()> {
+        let topics = match &notification.topic {
+            Some(topic) => std::slice::from_ref(topic),
+            None => self.topics.as_slice(),
+        };
+        if let [topic] = topics {
+            return self.publish(notification, topic).await;
+        }
+        let results = join_all(topics.iter().map(|topic| self.publish(notification, topic))).await;
+        let mut error = None;
+        let mut published = false;
+        for (topic, result) in topics.iter().zip(results) {
+            match result {
+                Ok(()) => published = true,
+                Err(e) => {
+                    let e = e.context(format!("failed to publish to ntfy topic {}", topic));
+                    warn!("{:#}", e);
+                    error = Some(e);
+                }
+            }
+        }
+        match error {
+            Some(e) if !published => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use super::{escape_html, Notification, Notifier, Priority};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// Either a numeric chat id or an `@channelusername`.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum ChatId {
+    Id(i64),
+    Username(String),
+}
+
+/// Sends notifications through a Telegram bot with the Bot API `sendMessage` method.
+pub struct TelegramNotifier {
+    client: reqwest::Client,
+    url: String,
+    chat_id: ChatId,
+}
+
+#[derive(Serialize)]
+struct SendMessage<'a> {
+    chat_id: &'a ChatId,
+    text: String,
+    parse_mode: &'static str,
+    disable_notification: bool,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    ok: bool,
+    description: Option<String>,
+}
+
+impl TelegramNotifier {
+    pub fn new(bot_token: &str, chat_id: &ChatId) -> Self {
+        TelegramNotifier::with_api("https://api.telegram.org", bot_token, chat_id)
+    }
+
+    /// Talks to the Bot API at `api_url` instead of Telegram's.
+    pub fn with_api(api_url: &str, bot_token: &str, chat_id: &ChatId) -> Self {
+        TelegramNotifier {
+            client: reqwest::Client::new(),
+            url: format!(
+                "{}/bot{}/sendMessage",
+                api_url.trim_end_matches('/'),
+                bot_token
+            ),
+            chat_id: chat_id.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for TelegramNotifier {
+    async fn notify(&self, notification: &Notification) -> Result<()> {
+        let body = SendMessage {
+            chat_id: &self.chat_id,
+            text: format!(
+                "<b>{}</b>\n{}",
+                escape_html(&notification.title),
+                escape_html(&notification.message)
+            ),
+            parse_mode: "HTML",
+            // low priority mail still shows up in the chat, just without a sound
+            disable_notification: notification.priority <= Priority::Low,
+        };
+        let response: Response = self
+            .client
+            .post(&self.url)
+            .json(&body)
+            .send()
+            .await?
+            .json()
+            .await?;
+        if !response.ok {
+            let description = response.description.unwrap_or_default();
+            warn!("telegram rejected the notification: {}", description);
+            bail!("telegram error: {}", description);
+        }
+        Ok(())
+    }
+}
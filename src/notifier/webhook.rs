@@ -0,0 +1,59 @@
+use super::{Notification, Notifier};
+use anyhow::Result;
+use async_trait::async_trait;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// POSTs every notification as JSON to an arbitrary URL.
+pub struct WebhookNotifier {
+    client: reqwest::Client,
+    url: String,
+    account: String,
+}
+
+#[derive(Serialize)]
+struct Body<'a> {
+    title: &'a str,
+    message: &'a str,
+    /// ntfy-style priority from 1 (min) to 5 (max).
+    priority: u8,
+    account: &'a str,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: &str, headers: &HashMap<String, String>, account: &str) -> Result<Self> {
+        let mut header_map = HeaderMap::new();
+        for (name, value) in headers {
+            header_map.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        Ok(WebhookNotifier {
+            client: reqwest::Client::builder()
+                .default_headers(header_map)
+                .build()?,
+            url: url.to_string(),
+            account: account.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    async fn notify(&self, notification: &Notification) -> Result<()> {
+        self.client
+            .post(&self.url)
+            .json(&Body {
+                title: &notification.title,
+                message: &notification.message,
+                priority: notification.priority.level(),
+                account: &self.account,
+            })
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
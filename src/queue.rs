@@ -0,0 +1,164 @@
+use crate::notifier::Notification;
+use anyhow::{Context, Result};
+use std::{
+    collections::VecDeque,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tracing::{info, warn};
+
+/// Notifications that failed every attempt, oldest first, waiting for the notifier to work again.
+///
+/// With a path every notification is also appended to that file as a line of JSON, so the queue
+/// survives restarts. Without one it only lives in memory. The file is only readable by its owner,
+/// since it holds subjects and previews, and action buttons are written without their token.
+pub struct NotificationQueue {
+    path: Option<PathBuf>,
+    capacity: usize,
+    pending: Mutex<VecDeque<Notification>>,
+}
+
+impl NotificationQueue {
+    /// Loads the queue file. A missing file is an empty queue, lines that can't be read are
+    /// skipped.
+    pub fn open(path: Option<PathBuf>, capacity: usize) -> Self {
+        let mut pending = VecDeque::new();
+        if let Some(path) = &path {
+            match read_queue(path) {
+                Ok(notifications) => pending = notifications,
+                Err(e) => warn!(path = %path.display(), "ignoring notification queue: {:#}", e),
+            }
+            if !pending.is_empty() {
+                info!(path = %path.display(), "{} queued notifications to send", pending.len());
+            }
+        }
+        let dropped = drop_oldest(&mut pending, capacity, 0);
+        let queue = NotificationQueue {
+            path,
+            capacity,
+            pending: Mutex::new(pending),
+        };
+        if dropped {
+            queue.rewrite(&queue.pending.lock().unwrap());
+        }
+        queue
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.lock().unwrap().is_empty()
+    }
+
+    /// Queues a notification, dropping the oldest ones when the queue is full.
+    pub fn push(&self, notification: Notification) {
+        let mut pending = self.pending.lock().unwrap();
+        let dropped = drop_oldest(&mut pending, self.capacity, 1);
+        pending.push_back(notification);
+        if dropped {
+            self.rewrite(&pending);
+        } else if let Some(path) = &self.path {
+            if let Err(e) = append(path, pending.back().unwrap()) {
+                warn!("{:#}", e);
+            }
+        }
+    }
+
+    /// Takes every queued notification to send them. Whatever couldn't be sent goes back with
+    /// `put_back`.
+    pub fn take(&self) -> VecDeque<Notification> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+
+    /// Returns `unsent` to the front of the queue, ahead of anything queued since `take`, and
+    /// writes the file to match.
+    pub fn put_back(&self, unsent: VecDeque<Notification>) {
+        let mut pending = self.pending.lock().unwrap();
+        for notification in unsent.into_iter().rev() {
+            pending.push_front(notification);
+        }
+        drop_oldest(&mut pending, self.capacity, 0);
+        self.rewrite(&pending);
+    }
+
+    fn rewrite(&self, pending: &VecDeque<Notification>) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Err(e) = write_queue(path, pending) {
+            warn!("{:#}", e);
+        }
+    }
+}
+
+/// Drops the oldest notifications until `room` more fit, returning whether any were dropped.
+fn drop_oldest(pending: &mut VecDeque<Notification>, capacity: usize, room: usize) -> bool {
+    let mut dropped = false;
+    while pending.len() + room > capacity {
+        let Some(notification) = pending.pop_front() else {
+            break;
+        };
+        warn!(
+            title = %notification.title,
+            subject = ?notification.mail.as_ref().map(|mail| &mail.subject),
+            "notification queue is full, dropping the oldest notification"
+        );
+        dropped = true;
+    }
+    dropped
+}
+
+fn read_queue(path: &Path) -> Result<VecDeque<Notification>> {
+    let buf = match fs::read_to_string(path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(VecDeque::new()),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(buf
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(notification) => Some(notification),
+            Err(e) => {
+                warn!(path = %path.display(), "skipping unreadable queued notification: {}", e);
+                None
+            }
+        })
+        .collect())
+}
+
+fn append(path: &Path, notification: &Notification) -> Result<()> {
+    let mut line = serde_json::to_vec(notification)?;
+    line.push(b'\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(&line))
+        .with_context(|| format!("failed to append to notification queue {}", path.display()))
+}
+
+/// Writes to a temporary file next to `path` and renames it over, like the state file.
+fn write_queue(path: &Path, pending: &VecDeque<Notification>) -> Result<()> {
+    let mut buf = Vec::new();
+    for notification in pending {
+        serde_json::to_writer(&mut buf, notification)?;
+        buf.push(b'\n');
+    }
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .and_then(|mut file| file.write_all(&buf))
+        .with_context(|| format!("failed to write {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("failed to replace notification queue {}", path.display()))?;
+    Ok(())
+}
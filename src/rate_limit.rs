@@ -0,0 +1,50 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A token bucket for `max_notifications_per_minute`: it starts full, holds at most one minute's
+/// worth of notifications and refills continuously.
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    /// Tokens added per second.
+    rate: f64,
+    refilled: Instant,
+    /// Messages that were held back since the last summary.
+    pub suppressed: usize,
+}
+
+impl TokenBucket {
+    pub fn per_minute(limit: u32, now: Instant) -> Self {
+        let capacity = f64::from(limit);
+        TokenBucket {
+            capacity,
+            tokens: capacity,
+            rate: capacity / 60.0,
+            refilled: now,
+            suppressed: 0,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.refilled = now;
+    }
+
+    /// Takes a token if there is one.
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// How long until `try_take` succeeds, zero if it would right now.
+    pub fn until_available(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        Duration::from_secs_f64(((1.0 - self.tokens) / self.rate).max(0.0))
+    }
+}
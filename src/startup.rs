@@ -0,0 +1,80 @@
+use crate::notifier::{Notification, Notifier, NtfyAuth, NtfyNotifier, Priority};
+use futures::future::join_all;
+use serde::Deserialize;
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+/// The `[startup_notification]` table: one notification once every account tried to connect.
+#[derive(Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StartupNotificationConfig {
+    pub ntfy_url: String,
+    pub ntfy_topic: String,
+    /// Access token for a protected ntfy server.
+    pub ntfy_token: Option<String>,
+}
+
+/// What went wrong in an account's first connection attempt, `None` if it connected.
+pub type FirstConnection = oneshot::Receiver<Option<String>>;
+
+/// Waits for the first connection attempt of every account and sums them up, e.g.
+/// "5/6 accounts connected", followed by the accounts that failed and why.
+pub async fn summary(accounts: Vec<(String, FirstConnection)>) -> Notification {
+    let total = accounts.len();
+    let (names, attempts): (Vec<_>, Vec<_>) = accounts.into_iter().unzip();
+    let failed = names
+        .into_iter()
+        .zip(join_all(attempts).await)
+        .filter_map(|(name, attempt)| match attempt {
+            Ok(None) => None,
+            Ok(Some(failure)) => Some(format!("{} ({})", name, failure)),
+            // the account was stopped by a reload before it got to connect
+            Err(_) => Some(format!("{} (stopped before connecting)", name)),
+        })
+        .collect::<Vec<_>>();
+    let message = format!("{}/{} accounts connected", total - failed.len(), total);
+    if failed.is_empty() {
+        return Notification::new("unseenmail started", message, Priority::Default)
+            .tag("white_check_mark");
+    }
+    Notification::new(
+        "unseenmail started",
+        format!("{}\n\nFailed: {}", message, failed.join(", ")),
+        Priority::High,
+    )
+    .tag("warning")
+}
+
+/// Sends the summary to the configured topic, unless unseenmail shuts down first.
+pub async fn notify(
+    config: StartupNotificationConfig,
+    accounts: Vec<(String, FirstConnection)>,
+    dry_run: bool,
+    shutdown: CancellationToken,
+) {
+    let notification = tokio::select! {
+        notification = summary(accounts) => notification,
+        _ = shutdown.cancelled() => return,
+    };
+    if dry_run {
+        info!(
+            title = %notification.title,
+            message = %notification.message,
+            "dry run, not sending notification"
+        );
+        return;
+    }
+    let result = match NtfyNotifier::new(
+        &config.ntfy_url,
+        std::slice::from_ref(&config.ntfy_topic),
+        config.ntfy_token.map(NtfyAuth::Token),
+        None,
+    ) {
+        Ok(notifier) => notifier.notify(&notification).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        error!("failed to send the startup notification: {:#}", e);
+    }
+}
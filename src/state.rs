@@ -0,0 +1,140 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tracing::{info, warn};
+
+/// Per-mailbox progress that survives restarts.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MailboxState {
+    pub last_notified: u32,
+    /// `last_notified` only means something for this UIDVALIDITY, unknown in older state files.
+    #[serde(default)]
+    pub uid_validity: Option<u32>,
+    /// The HIGHESTMODSEQ of the last check on a CONDSTORE server, nothing changed while it stays
+    /// the same.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub highest_modseq: Option<u64>,
+}
+
+/// How many notified `Message-ID`s are remembered per account.
+const MESSAGE_ID_CAPACITY: usize = 1000;
+
+/// Everything we persist, keyed by account name and then by mailbox name.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    #[serde(default)]
+    accounts: HashMap<String, HashMap<String, MailboxState>>,
+    /// Recently notified `Message-ID`s per account, oldest first.
+    #[serde(default)]
+    message_ids: HashMap<String, VecDeque<String>>,
+    /// Whether there are changes that haven't been written yet.
+    #[serde(skip)]
+    dirty: bool,
+}
+
+/// Shared handle to the state file, used by every account task.
+///
+/// Without a configured path the state only lives in memory, which is the old behavior.
+pub struct StateStore {
+    path: Option<PathBuf>,
+    state: Mutex<State>,
+}
+
+impl StateStore {
+    /// Loads the state file. A missing or malformed file is treated as a fresh start.
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let state = match &path {
+            Some(path) => match read_state(path) {
+                Ok(Some(state)) => state,
+                Ok(None) => {
+                    info!(path = %path.display(), "state file does not exist yet, starting fresh");
+                    State::default()
+                }
+                Err(e) => {
+                    warn!(path = %path.display(), "ignoring state file: {:#}", e);
+                    State::default()
+                }
+            },
+            None => State::default(),
+        };
+        StateStore {
+            path,
+            state: Mutex::new(state),
+        }
+    }
+
+    pub fn mailboxes(&self, account: &str) -> HashMap<String, MailboxState> {
+        let state = self.state.lock().unwrap();
+        state.accounts.get(account).cloned().unwrap_or_default()
+    }
+
+    /// Records the state of one mailbox and writes the state file if anything changed.
+    pub fn update(&self, account: &str, mailbox: &str, mailbox_state: &MailboxState) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let mailboxes = state.accounts.entry(account.to_string()).or_default();
+        if mailboxes.get(mailbox) != Some(mailbox_state) {
+            mailboxes.insert(mailbox.to_string(), mailbox_state.clone());
+            state.dirty = true;
+        }
+        if !std::mem::take(&mut state.dirty) {
+            return Ok(());
+        }
+        match &self.path {
+            Some(path) => write_state(path, &state),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether `message_id` was notified, without remembering it, for `--dry-run`.
+    pub fn was_notified(&self, account: &str, message_id: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .message_ids
+            .get(account)
+            .is_some_and(|message_ids| message_ids.iter().any(|id| id == message_id))
+    }
+
+    /// Remembers that `message_id` was notified and returns whether it was new, so the same
+    /// message is never notified twice even if its UID changes. It is written with the next
+    /// `update`.
+    pub fn record_message_id(&self, account: &str, message_id: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let message_ids = state.message_ids.entry(account.to_string()).or_default();
+        if message_ids.iter().any(|id| id == message_id) {
+            return false;
+        }
+        if message_ids.len() >= MESSAGE_ID_CAPACITY {
+            message_ids.pop_front();
+        }
+        message_ids.push_back(message_id.to_string());
+        state.dirty = true;
+        true
+    }
+}
+
+fn read_state(path: &Path) -> Result<Option<State>> {
+    let buf = match fs::read_to_string(path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(Some(serde_json::from_str(&buf)?))
+}
+
+/// Writes to a temporary file next to `path` and renames it over, so a crash never leaves a
+/// half-written state file behind.
+fn write_state(path: &Path, state: &State) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    fs::write(&tmp_path, serde_json::to_vec_pretty(state)?)
+        .with_context(|| format!("failed to write {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("failed to replace state file {}", path.display()))?;
+    Ok(())
+}
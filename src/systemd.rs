@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    env, io,
+    os::unix::net::{SocketAddr, UnixDatagram},
+    process,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+
+/// Tells systemd about startup and liveness with the sd_notify protocol, for `Type=notify`
+/// services and `WatchdogSec`.
+///
+/// See <https://www.freedesktop.org/software/systemd/man/latest/sd_notify.html>.
+pub struct SdNotify {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+}
+
+impl SdNotify {
+    /// Connects to `NOTIFY_SOCKET` when systemd set it, which it only does for services that
+    /// expect notifications.
+    pub fn from_env() -> Option<SdNotify> {
+        let path = env::var("NOTIFY_SOCKET").ok()?;
+        match SdNotify::new(&path) {
+            Ok(notify) => Some(notify),
+            Err(e) => {
+                warn!("failed to use NOTIFY_SOCKET {}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    /// `path` is a socket path, or an abstract socket name when it starts with `@`.
+    pub fn new(path: &str) -> io::Result<SdNotify> {
+        let addr = match path.strip_prefix('@') {
+            Some(name) => abstract_addr(name)?,
+            None => SocketAddr::from_pathname(path)?,
+        };
+        Ok(SdNotify {
+            socket: UnixDatagram::unbound()?,
+            addr,
+        })
+    }
+
+    /// Sends a state such as `READY=1`. Failures are only logged, systemd not listening is no
+    /// reason to stop watching mail.
+    pub fn notify(&self, state: &str) {
+        debug!("sd_notify {}", state);
+        if let Err(e) = self.socket.send_to_addr(state.as_bytes(), &self.addr) {
+            warn!("failed to notify systemd: {}", e);
+        }
+    }
+
+    /// How often to send `WATCHDOG=1`, half of `WatchdogSec` as the man page recommends. None
+    /// without a watchdog or when it is meant for another process.
+    pub fn watchdog_interval() -> Option<Duration> {
+        if let Ok(pid) = env::var("WATCHDOG_PID") {
+            if pid.parse() != Ok(process::id()) {
+                return None;
+            }
+        }
+        let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+        (usec > 0).then(|| Duration::from_micros(usec / 2))
+    }
+}
+
+/// When each account's loop last made progress, so the watchdog is only fed while none of them
+/// is stuck.
+#[derive(Clone)]
+pub struct Liveness {
+    window: Duration,
+    beats: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl Liveness {
+    /// An account counts as stuck once it didn't beat for `window`.
+    pub fn new(window: Duration) -> Liveness {
+        Liveness {
+            window,
+            beats: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn beat(&self, account: &str) {
+        let mut beats = self.beats.lock().unwrap();
+        beats.insert(account.to_string(), Instant::now());
+    }
+
+    /// Stops watching an account whose task ended.
+    pub fn forget(&self, account: &str) {
+        self.beats.lock().unwrap().remove(account);
+    }
+
+    /// How often an account beats while it waits for something that is bound to end.
+    pub fn tick(&self) -> Duration {
+        self.window / 2
+    }
+
+    /// The accounts that didn't beat within the window, sorted by name.
+    pub fn stuck(&self) -> Vec<String> {
+        let beats = self.beats.lock().unwrap();
+        let mut stuck: Vec<_> = beats
+            .iter()
+            .filter(|(_, beat)| beat.elapsed() > self.window)
+            .map(|(account, _)| account.clone())
+            .collect();
+        stuck.sort_unstable();
+        stuck
+    }
+}
+
+/// Sends `WATCHDOG=1` every `interval` as long as no account is stuck, so systemd restarts the
+/// service once one of them stops making progress.
+pub async fn watchdog(sd_notify: Arc<SdNotify>, liveness: Liveness, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    let mut healthy = true;
+    loop {
+        ticks.tick().await;
+        let stuck = liveness.stuck();
+        if stuck.is_empty() {
+            if !std::mem::replace(&mut healthy, true) {
+                info!("every account is making progress again, feeding the watchdog");
+            }
+            sd_notify.notify("WATCHDOG=1");
+        } else if std::mem::replace(&mut healthy, false) {
+            warn!(
+                "no progress from [{}], no longer feeding the watchdog",
+                stuck.join("], [")
+            );
+        }
+    }
+}
+
+/// The address of the abstract socket `name`, from a `NOTIFY_SOCKET` like `@name`.
+#[cfg(target_os = "linux")]
+fn abstract_addr(name: &str) -> io::Result<SocketAddr> {
+    use std::os::linux::net::SocketAddrExt;
+    SocketAddr::from_abstract_name(name)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn abstract_addr(_name: &str) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "abstract sockets are only supported on Linux",
+    ))
+}
@@ -0,0 +1,52 @@
+//! The config template printed by `--print-config-template`.
+//!
+//! The keys come from `Config` and `Account` themselves, in the order their fields are declared,
+//! and every key's example and description from the `KeyDoc` tables next to them in `config`.
+
+use crate::config::{self, KeyDoc};
+
+/// Every top-level and account key, optional ones commented out.
+pub fn config_template() -> String {
+    let mut template = String::from(
+        "# unseenmail config, see https://github.com/DianQK/unseenmail#configuration\n\
+         # Optional keys are commented out, with an example value.\n\n",
+    );
+    section(
+        &mut template,
+        config::config_keys()
+            .iter()
+            .filter(|key| **key != "accounts"),
+        config::CONFIG_KEY_DOCS,
+    );
+    template.push_str("\n[[accounts]]\n");
+    section(
+        &mut template,
+        config::account_keys().iter(),
+        config::ACCOUNT_KEY_DOCS,
+    );
+    template
+}
+
+fn section<'a>(
+    template: &mut String,
+    keys: impl Iterator<Item = &'a &'static str>,
+    docs: &[KeyDoc],
+) {
+    for key in keys {
+        let Some(doc) = docs.iter().find(|doc| doc.key == *key) else {
+            // the test makes sure this doesn't happen
+            template.push_str(&format!("# {} = ...\n", key));
+            continue;
+        };
+        if !doc.required {
+            template.push_str("# ");
+        }
+        template.push_str(&format!(
+            "{} = {} # {}{}\n",
+            doc.key,
+            doc.example,
+            if doc.required { "" } else { "optional, " },
+            doc.description
+        ));
+    }
+}
@@ -0,0 +1,463 @@
+//! A minimal in-process IMAP server with a single mailbox, just capable enough for the commands
+//! `UnseenMail` sends: LOGIN, AUTHENTICATE, CAPABILITY, SELECT, STATUS, UID SEARCH, UID FETCH, UID STORE,
+//! UID COPY, UID MOVE, (UID) EXPUNGE, IDLE, LIST and LOGOUT. SEARCH ignores its criteria and returns
+//! every message, LIST always returns the same few folders, and mail copied or moved elsewhere is
+//! gone. With CONDSTORE advertised, SELECT reports a HIGHESTMODSEQ that every delivery and STORE
+//! raises, and with COMPRESS=DEFLATE advertised it speaks COMPRESS too.
+
+use crate::compress::{CompressSwitch, Compressible};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, WriteHalf},
+    net::{TcpListener, TcpStream},
+    sync::Notify,
+    task,
+};
+
+type Writer = WriteHalf<Compressible<TcpStream>>;
+
+/// The challenge sent for `AUTHENTICATE CRAM-MD5`, the one from the example in RFC 2195.
+const CRAM_MD5_CHALLENGE: &str = "<1896.697170952@postoffice.reston.mci.net>";
+
+#[derive(Clone)]
+pub struct MockMessage {
+    pub uid: u32,
+    pub flags: Vec<String>,
+    pub header: String,
+    pub text: String,
+    /// Sent for `BODYSTRUCTURE`, a single text/plain part unless a test changes it.
+    pub bodystructure: String,
+}
+
+impl MockMessage {
+    /// A plain-text message with the usual headers.
+    pub fn new(uid: u32, from: &str, subject: &str, text: &str) -> Self {
+        MockMessage {
+            uid,
+            flags: Vec::new(),
+            header: format!(
+                "From: {}\r\nSubject: {}\r\nMessage-ID: <{}@mock>\r\n\r\n",
+                from, subject, uid
+            ),
+            text: format!("{}\r\n", text),
+            bodystructure: format!(
+                "(\"TEXT\" \"PLAIN\" (\"CHARSET\" \"utf-8\") NIL NIL \"7BIT\" {} 1)",
+                text.len() + 2
+            ),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Mailbox {
+    uid_validity: u32,
+    messages: Vec<MockMessage>,
+    /// Every command received, without its tag.
+    commands: Vec<String>,
+    /// Extra capabilities to advertise, e.g. `MOVE`.
+    extensions: Vec<String>,
+    highest_modseq: u64,
+    /// Answer LOGIN with NO, as for a wrong password.
+    reject_logins: bool,
+    /// Close the connection instead of answering the next UID FETCH.
+    drop_next_fetch: bool,
+}
+
+pub struct MockServer {
+    addr: SocketAddr,
+    mailbox: Arc<Mutex<Mailbox>>,
+    new_mail: Arc<Notify>,
+    hang_up: Arc<Notify>,
+}
+
+impl MockServer {
+    pub async fn start(messages: Vec<MockMessage>) -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = MockServer {
+            addr: listener.local_addr().unwrap(),
+            mailbox: Arc::new(Mutex::new(Mailbox {
+                uid_validity: 1,
+                messages,
+                commands: Vec::new(),
+                extensions: Vec::new(),
+                highest_modseq: 1,
+                reject_logins: false,
+                drop_next_fetch: false,
+            })),
+            new_mail: Arc::new(Notify::new()),
+            hang_up: Arc::new(Notify::new()),
+        };
+        let mailbox = server.mailbox.clone();
+        let new_mail = server.new_mail.clone();
+        let hang_up = server.hang_up.clone();
+        task::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                task::spawn(serve(
+                    stream,
+                    mailbox.clone(),
+                    new_mail.clone(),
+                    hang_up.clone(),
+                ));
+            }
+        });
+        server
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    /// Delivers a message, clients in IDLE are told about it right away.
+    pub fn deliver(&self, message: MockMessage) {
+        let mut mailbox = self.mailbox.lock().unwrap();
+        mailbox.messages.push(message);
+        mailbox.highest_modseq += 1;
+        drop(mailbox);
+        self.new_mail.notify_waiters();
+    }
+
+    /// Advertises `capability` from now on, the mock supports CONDSTORE, MOVE and UIDPLUS.
+    pub fn reject_logins(&self) {
+        self.mailbox.lock().unwrap().reject_logins = true;
+    }
+
+    /// Hangs up on the next client that sends UID FETCH, before any message is sent.
+    pub fn drop_next_fetch(&self) {
+        self.mailbox.lock().unwrap().drop_next_fetch = true;
+    }
+
+    pub fn advertise(&self, capability: &str) {
+        let mut mailbox = self.mailbox.lock().unwrap();
+        mailbox.extensions.push(capability.to_string());
+    }
+
+    pub fn uids(&self) -> Vec<u32> {
+        let mailbox = self.mailbox.lock().unwrap();
+        mailbox.messages.iter().map(|message| message.uid).collect()
+    }
+
+    /// Closes the connection of every client in IDLE without a word, like a crashed server.
+    pub fn hang_up(&self) {
+        self.hang_up.notify_waiters();
+    }
+
+    pub fn flags(&self, uid: u32) -> Vec<String> {
+        let mailbox = self.mailbox.lock().unwrap();
+        let message = mailbox.messages.iter().find(|message| message.uid == uid);
+        message
+            .map(|message| message.flags.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn commands(&self) -> Vec<String> {
+        self.mailbox.lock().unwrap().commands.clone()
+    }
+}
+
+async fn serve(
+    stream: TcpStream,
+    mailbox: Arc<Mutex<Mailbox>>,
+    new_mail: Arc<Notify>,
+    hang_up: Arc<Notify>,
+) {
+    let compress = CompressSwitch::default();
+    let (read, mut write) = tokio::io::split(Compressible::new(stream, compress.clone()));
+    let mut lines = BufReader::new(read).lines();
+    send(&mut write, "* OK [CAPABILITY IMAP4rev1 IDLE] mock ready").await;
+    // the message count this client was last told about
+    let mut exists = 0;
+    while let Ok(Some(line)) = lines.next_line().await {
+        let (tag, command) = line.split_once(' ').unwrap_or((line.as_str(), ""));
+        mailbox.lock().unwrap().commands.push(command.to_string());
+        let verb = command.split(' ').next().unwrap_or_default().to_uppercase();
+        let args = command.split_once(' ').map_or("", |(_, args)| args);
+        let untagged = match verb.as_str() {
+            "CAPABILITY" => {
+                let mailbox = mailbox.lock().unwrap();
+                vec![format!(
+                    "* CAPABILITY IMAP4rev1 IDLE AUTH=PLAIN AUTH=CRAM-MD5{}",
+                    mailbox
+                        .extensions
+                        .iter()
+                        .map(|extension| format!(" {}", extension))
+                        .collect::<String>()
+                )]
+            }
+            "AUTHENTICATE" => {
+                // any response is accepted, it is only recorded like a command
+                if args.eq_ignore_ascii_case("CRAM-MD5") {
+                    send(
+                        &mut write,
+                        &format!("+ {}", STANDARD.encode(CRAM_MD5_CHALLENGE)),
+                    )
+                    .await;
+                } else {
+                    send(&mut write, "+ ").await;
+                }
+                let Ok(Some(response)) = lines.next_line().await else {
+                    return;
+                };
+                mailbox.lock().unwrap().commands.push(response);
+                Vec::new()
+            }
+            "LOGIN" if mailbox.lock().unwrap().reject_logins => {
+                send(
+                    &mut write,
+                    &format!("{} NO [AUTHENTICATIONFAILED] invalid credentials", tag),
+                )
+                .await;
+                continue;
+            }
+            "LOGIN" | "NOOP" => Vec::new(),
+            "COMPRESS"
+                if mailbox
+                    .lock()
+                    .unwrap()
+                    .extensions
+                    .iter()
+                    .any(|extension| extension == "COMPRESS=DEFLATE") =>
+            {
+                send(&mut write, &format!("{} OK DEFLATE active", tag)).await;
+                compress.enable();
+                continue;
+            }
+            "EXPUNGE" => expunge(&mut mailbox.lock().unwrap(), "1:*"),
+            "LIST" => vec![
+                String::from(r#"* LIST (\HasNoChildren \Sent) "." "INBOX.Sent""#),
+                String::from(r#"* LIST (\HasChildren) "." "INBOX""#),
+                String::from(r#"* LIST (\Noselect \HasChildren) "." "Archive""#),
+                String::from(r#"* LIST (\HasNoChildren) "." "Archive.2024""#),
+            ],
+            "SELECT" | "EXAMINE" => {
+                let mailbox = mailbox.lock().unwrap();
+                exists = mailbox.messages.len();
+                select(&mailbox)
+            }
+            "STATUS" => {
+                let mailbox = mailbox.lock().unwrap();
+                let unseen = mailbox
+                    .messages
+                    .iter()
+                    .filter(|message| !message.flags.iter().any(|flag| flag == "\\Seen"))
+                    .count();
+                vec![format!("* STATUS INBOX (UNSEEN {})", unseen)]
+            }
+            "UID"
+                if args.to_uppercase().starts_with("FETCH ")
+                    && std::mem::take(&mut mailbox.lock().unwrap().drop_next_fetch) =>
+            {
+                return;
+            }
+            "UID" => match uid_command(&mut write, &mailbox, args).await {
+                Some(untagged) => untagged,
+                None => {
+                    send(&mut write, &format!("{} BAD unknown UID command", tag)).await;
+                    continue;
+                }
+            },
+            "IDLE" => {
+                send(&mut write, "+ idling").await;
+                loop {
+                    let notified = new_mail.notified();
+                    let hung_up = hang_up.notified();
+                    tokio::pin!(notified, hung_up);
+                    notified.as_mut().enable();
+                    hung_up.as_mut().enable();
+                    let count = mailbox.lock().unwrap().messages.len();
+                    if count > exists {
+                        exists = count;
+                        send(&mut write, &format!("* {} EXISTS", exists)).await;
+                    }
+                    tokio::select! {
+                        line = lines.next_line() => {
+                            if !matches!(line, Ok(Some(line)) if line.eq_ignore_ascii_case("DONE")) {
+                                return;
+                            }
+                            break;
+                        }
+                        _ = notified => {}
+                        _ = hung_up => return,
+                    }
+                }
+                Vec::new()
+            }
+            "LOGOUT" => {
+                send(&mut write, "* BYE logging out").await;
+                send(&mut write, &format!("{} OK LOGOUT completed", tag)).await;
+                return;
+            }
+            _ => {
+                send(&mut write, &format!("{} BAD unknown command", tag)).await;
+                continue;
+            }
+        };
+        for response in untagged {
+            send(&mut write, &response).await;
+        }
+        send(&mut write, &format!("{} OK {} completed", tag, verb)).await;
+    }
+}
+
+fn select(mailbox: &Mailbox) -> Vec<String> {
+    let uid_next = mailbox.messages.iter().map(|m| m.uid).max().unwrap_or(0) + 1;
+    let mut untagged = vec![
+        format!("* {} EXISTS", mailbox.messages.len()),
+        String::from("* 0 RECENT"),
+        String::from("* FLAGS (\\Seen \\Flagged)"),
+        String::from("* OK [PERMANENTFLAGS (\\Seen \\Flagged \\*)] flags permitted"),
+        format!("* OK [UIDVALIDITY {}] UIDs valid", mailbox.uid_validity),
+        format!("* OK [UIDNEXT {}] predicted next UID", uid_next),
+    ];
+    if mailbox
+        .extensions
+        .iter()
+        .any(|extension| extension == "CONDSTORE")
+    {
+        untagged.push(format!(
+            "* OK [HIGHESTMODSEQ {}] highest",
+            mailbox.highest_modseq
+        ));
+    }
+    untagged
+}
+
+/// Handles `UID SEARCH`, `UID FETCH` and `UID STORE`. FETCH responses contain literals, so they
+/// are written here directly and the returned untagged lines are empty.
+async fn uid_command(
+    write: &mut Writer,
+    mailbox: &Mutex<Mailbox>,
+    args: &str,
+) -> Option<Vec<String>> {
+    let (command, args) = args.split_once(' ').unwrap_or((args, ""));
+    match command.to_uppercase().as_str() {
+        "SEARCH" => {
+            let mailbox = mailbox.lock().unwrap();
+            let uids = mailbox
+                .messages
+                .iter()
+                .map(|message| format!(" {}", message.uid))
+                .collect::<String>();
+            Some(vec![format!("* SEARCH{}", uids)])
+        }
+        "FETCH" => {
+            let (set, items) = args.split_once(' ').unwrap_or((args, ""));
+            let items = items.to_uppercase();
+            let with_text = items.contains("TEXT");
+            let with_structure = items.contains("BODYSTRUCTURE");
+            let messages = matching(&mailbox.lock().unwrap(), set);
+            for (seq, message) in messages {
+                let mut response = format!(
+                    "* {} FETCH (UID {} FLAGS ({}) BODY[HEADER] {{{}}}\r\n{}",
+                    seq,
+                    message.uid,
+                    message.flags.join(" "),
+                    message.header.len(),
+                    message.header
+                );
+                if with_text {
+                    response.push_str(&format!(
+                        " BODY[TEXT]<0> {{{}}}\r\n{}",
+                        message.text.len(),
+                        message.text
+                    ));
+                }
+                if with_structure {
+                    response.push_str(&format!(" BODYSTRUCTURE {}", message.bodystructure));
+                }
+                response.push_str(")\r\n");
+                write.write_all(response.as_bytes()).await.ok();
+                write.flush().await.ok();
+            }
+            Some(Vec::new())
+        }
+        "STORE" => {
+            let (set, flags) = args.split_once(' ').unwrap_or((args, ""));
+            let mut mailbox = mailbox.lock().unwrap();
+            let uids: Vec<_> = matching(&mailbox, set)
+                .into_iter()
+                .map(|(_, m)| m.uid)
+                .collect();
+            // only adding flags, e.g. `+FLAGS.SILENT (\Seen)`
+            let flags = flags
+                .split_once('(')
+                .and_then(|(_, flags)| flags.strip_suffix(')'))
+                .unwrap_or_default();
+            for message in &mut mailbox.messages {
+                if uids.contains(&message.uid) {
+                    message.flags.extend(flags.split(' ').map(String::from));
+                }
+            }
+            mailbox.highest_modseq += 1;
+            Some(Vec::new())
+        }
+        "COPY" => Some(Vec::new()),
+        "MOVE" => {
+            let (set, _) = args.split_once(' ').unwrap_or((args, ""));
+            let mut mailbox = mailbox.lock().unwrap();
+            let uids: Vec<_> = matching(&mailbox, set)
+                .into_iter()
+                .map(|(_, m)| m.uid)
+                .collect();
+            mailbox
+                .messages
+                .retain(|message| !uids.contains(&message.uid));
+            Some(Vec::new())
+        }
+        "EXPUNGE" => Some(expunge(&mut mailbox.lock().unwrap(), args)),
+        _ => None,
+    }
+}
+
+/// Removes the `\Deleted` messages in the UID set `set`, returning the untagged EXPUNGEs.
+fn expunge(mailbox: &mut Mailbox, set: &str) -> Vec<String> {
+    let deleted: Vec<_> = matching(mailbox, set)
+        .into_iter()
+        .filter(|(_, message)| message.flags.iter().any(|flag| flag == "\\Deleted"))
+        .collect();
+    // every EXPUNGE renumbers the messages after it, so the last goes first
+    let untagged = deleted
+        .iter()
+        .rev()
+        .map(|(seq, _)| format!("* {} EXPUNGE", seq))
+        .collect();
+    mailbox.messages.retain(|message| {
+        !deleted
+            .iter()
+            .any(|(_, deleted)| deleted.uid == message.uid)
+    });
+    untagged
+}
+
+/// The messages in a UID set such as `1,3:5` or `4:*`, with their sequence numbers.
+fn matching(mailbox: &Mailbox, set: &str) -> Vec<(usize, MockMessage)> {
+    let in_set = |uid: u32| {
+        set.split(',').any(|range| match range.split_once(':') {
+            Some((start, end)) => {
+                let start = start.parse().unwrap_or(0);
+                let end = end.parse().unwrap_or(u32::MAX);
+                (start.min(end)..=start.max(end)).contains(&uid)
+            }
+            None => range.parse() == Ok(uid),
+        })
+    };
+    mailbox
+        .messages
+        .iter()
+        .enumerate()
+        .filter(|(_, message)| in_set(message.uid))
+        .map(|(i, message)| (i + 1, message.clone()))
+        .collect()
+}
+
+/// Writes and flushes, a compressed stream only sends anything on flush.
+async fn send(write: &mut Writer, line: &str) {
+    write
+        .write_all(format!("{}\r\n", line).as_bytes())
+        .await
+        .ok();
+    write.flush().await.ok();
+}
@@ -0,0 +1,2572 @@
+mod mock_imap;
+
+use self::mock_imap::{MockMessage, MockServer};
+use crate::{
+    actions::{Actions, ActionsConfig},
+    config::{Account, Config},
+    filter::Filters,
+    metrics::Metrics,
+    notifier::{self, MemoryNotifier, Notification, Priority},
+    queue::NotificationQueue,
+    rate_limit::TokenBucket,
+    startup,
+    state::{MailboxState, StateStore},
+    systemd::{self, Liveness, SdNotify},
+    IdleOutcome, UnseenMail,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::future::join_all;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    sync::Semaphore,
+    task,
+};
+use tokio_util::sync::CancellationToken;
+
+fn account(server: &MockServer) -> Account {
+    account_with(server, "")
+}
+
+/// The test account with `extra` appended to its table.
+fn account_with(server: &MockServer, extra: &str) -> Account {
+    let config = Config::from_toml(&format!(
+        r#"
+        [[accounts]]
+        name = "test"
+        server = "127.0.0.1"
+        port = {}
+        username = "user"
+        password = "password"
+        encryption = "none"
+        allow_insecure = true
+        ntfy_url = "http://127.0.0.1:1"
+        ntfy_topic = "test"
+        {}
+        "#,
+        server.port(),
+        extra
+    ))
+    .unwrap();
+    config.accounts.into_iter().next().unwrap()
+}
+
+fn unseen_mail(account: Account, notifier: &MemoryNotifier) -> UnseenMail {
+    UnseenMail::with_notifier(
+        account,
+        Box::new(notifier.clone()),
+        Arc::new(StateStore::load(None)),
+        Arc::new(Metrics::default()),
+        None,
+        None,
+        false,
+    )
+}
+
+#[tokio::test]
+async fn check_once_notifies_new_mail() {
+    let server = MockServer::start(vec![MockMessage::new(
+        1,
+        "Alice <alice@example.com>",
+        "hello",
+        "Hi there",
+    )])
+    .await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(account(&server), &notifier);
+    let mut session = unseen_mail.new_session().await.unwrap();
+    let mut mailbox = MailboxState::default();
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut mailbox)
+        .await
+        .unwrap();
+
+    let sent = notifier.sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].title, "@test has new mail in INBOX");
+    assert_eq!(
+        sent[0].message,
+        "From: Alice <alice@example.com>\nSubject: hello\n\nHi there"
+    );
+    assert_eq!(sent[0].priority, Priority::Default);
+    assert_eq!(mailbox.last_notified, 1);
+    assert_eq!(mailbox.uid_validity, Some(1));
+}
+
+#[tokio::test]
+async fn check_once_skips_notified_mail() {
+    let server = MockServer::start(vec![
+        MockMessage::new(1, "alice@example.com", "old", "old"),
+        MockMessage::new(2, "bob@example.com", "new", "new"),
+    ])
+    .await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(account(&server), &notifier);
+    let mut session = unseen_mail.new_session().await.unwrap();
+    let mut mailbox = MailboxState {
+        last_notified: 1,
+        uid_validity: Some(1),
+        ..MailboxState::default()
+    };
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut mailbox)
+        .await
+        .unwrap();
+
+    let sent = notifier.sent();
+    assert_eq!(sent.len(), 1);
+    assert!(sent[0].message.contains("Subject: new"));
+    assert!(server.commands().contains(&String::from(
+        "UID FETCH 2 (FLAGS BODY.PEEK[HEADER] BODY.PEEK[TEXT]<0.16384>)"
+    )));
+}
+
+#[tokio::test]
+async fn idle_wait_wakes_up_for_new_mail() {
+    let server = MockServer::start(Vec::new()).await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(account(&server), &notifier);
+    let mut session = unseen_mail.new_session().await.unwrap();
+    session.select("INBOX").await.unwrap();
+
+    let shutdown = CancellationToken::new();
+    let idle = unseen_mail.idle_wait(session, Duration::from_secs(30), &shutdown);
+    let deliver = async {
+        // deliver while the client is idling rather than before it selected the folder
+        while !server.commands().iter().any(|command| command == "IDLE") {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        server.deliver(MockMessage::new(1, "alice@example.com", "hello", "hi"));
+    };
+    let ((_, outcome), ()) = tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::join!(async { idle.await.unwrap() }, deliver)
+    })
+    .await
+    .unwrap();
+    assert!(matches!(outcome, IdleOutcome::NewData));
+}
+
+#[tokio::test]
+async fn loop_check_only_searches_for_new_data_or_a_due_poll() {
+    let server = MockServer::start(Vec::new()).await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(
+        account_with(&server, "idle_timeout_secs = 1\npoll_interval_secs = 2"),
+        &notifier,
+    );
+    let session = unseen_mail.new_session().await.unwrap();
+    let count = |name: &str| {
+        server
+            .commands()
+            .iter()
+            .filter(|command| command.starts_with(name))
+            .count()
+    };
+    let wait_for = |name: &'static str, n: usize| async move {
+        while count(name) < n {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+
+    let shutdown = CancellationToken::new();
+    let mut mailboxes = HashMap::new();
+    let watch = unseen_mail.loop_check(session, &mut mailboxes, &shutdown);
+    let steps = async {
+        // renewing the IDLE after a second doesn't search
+        wait_for("IDLE", 2).await;
+        assert_eq!(count("UID SEARCH"), 1);
+        // new data does, right away
+        server.deliver(MockMessage::new(1, "a@example.com", "hi", "hi"));
+        wait_for("UID SEARCH", 2).await;
+        // and so does the poll two seconds later, after renewing the IDLE once in between
+        wait_for("UID SEARCH", 3).await;
+        shutdown.cancel();
+        let commands = server.commands();
+        let searches = commands
+            .iter()
+            .enumerate()
+            .filter(|(_, command)| command.starts_with("UID SEARCH"))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        let idles = commands[searches[1]..searches[2]]
+            .iter()
+            .filter(|command| *command == "IDLE")
+            .count();
+        assert_eq!(idles, 2);
+    };
+    let (result, ()) = tokio::time::timeout(Duration::from_secs(10), async {
+        tokio::join!(watch, steps)
+    })
+    .await
+    .unwrap();
+    result.unwrap();
+    assert_eq!(notifier.sent().len(), 1);
+}
+
+#[tokio::test]
+async fn idle_wait_times_out() {
+    let server = MockServer::start(Vec::new()).await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(account(&server), &notifier);
+    let mut session = unseen_mail.new_session().await.unwrap();
+    session.select("INBOX").await.unwrap();
+
+    let (_, outcome) = unseen_mail
+        .idle_wait(
+            session,
+            Duration::from_millis(50),
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+    assert!(matches!(outcome, IdleOutcome::Timeout));
+    assert!(server.commands().contains(&String::from("IDLE")));
+}
+
+#[tokio::test]
+async fn loop_check_fails_when_the_server_hangs_up_during_idle() {
+    let server = MockServer::start(Vec::new()).await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(account(&server), &notifier);
+    let session = unseen_mail.new_session().await.unwrap();
+
+    let shutdown = CancellationToken::new();
+    let mut mailboxes = HashMap::new();
+    let watch = unseen_mail.loop_check(session, &mut mailboxes, &shutdown);
+    let hang_up = async {
+        while !server.commands().iter().any(|command| command == "IDLE") {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        server.hang_up();
+    };
+    let (result, ()) = tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::join!(watch, hang_up)
+    })
+    .await
+    .unwrap();
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn check_once_batches_several_messages() {
+    let server = MockServer::start(vec![
+        MockMessage::new(1, "alice@example.com", "first", "one"),
+        MockMessage::new(2, "bob@example.com", "second", "two"),
+    ])
+    .await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(
+        account_with(&server, "batch_notifications = true"),
+        &notifier,
+    );
+    let mut session = unseen_mail.new_session().await.unwrap();
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut MailboxState::default())
+        .await
+        .unwrap();
+
+    let sent = notifier.sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].title, "@test has 2 new messages in INBOX");
+    assert_eq!(
+        sent[0].message,
+        "alice@example.com: first\nbob@example.com: second"
+    );
+}
+
+#[tokio::test]
+async fn report_error_goes_through_the_notifier() {
+    let server = MockServer::start(Vec::new()).await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(account(&server), &notifier);
+    unseen_mail
+        .report_error("connection refused")
+        .await
+        .unwrap();
+
+    let sent = notifier.sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].title, "@test connection failed");
+    assert_eq!(sent[0].message, "connection refused");
+    assert_eq!(sent[0].tags, ["warning"]);
+}
+
+#[tokio::test]
+async fn startup_notification_sums_up_the_first_connections() {
+    let server = MockServer::start(Vec::new()).await;
+    let mut down = account(&server);
+    down.name = String::from("down");
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    down.port = listener.local_addr().unwrap().port();
+    drop(listener);
+    let notifier = MemoryNotifier::default();
+    let mut first_connections = Vec::new();
+    let unseen_mails = [account(&server), down].map(|account| {
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        first_connections.push((account.name.clone(), receiver));
+        unseen_mail(account, &notifier).report_first_connection(Some(sender))
+    });
+
+    let shutdown = CancellationToken::new();
+    let watch = join_all(
+        unseen_mails
+            .iter()
+            .map(|unseen_mail| unseen_mail.watch(&shutdown)),
+    );
+    let summary = async {
+        let summary = startup::summary(first_connections).await;
+        shutdown.cancel();
+        summary
+    };
+    let (_, summary) = tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::join!(watch, summary)
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        summary.message,
+        "1/2 accounts connected\n\nFailed: down (can't reach the server)"
+    );
+    assert_eq!(summary.priority, Priority::High);
+}
+
+#[tokio::test]
+async fn watch_reports_failures_after_alert_after_secs() {
+    let server = MockServer::start(Vec::new()).await;
+    let mut account = account_with(&server, "alert_after_secs = 0");
+    // a port nothing listens on anymore
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    account.port = listener.local_addr().unwrap().port();
+    drop(listener);
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(account, &notifier);
+
+    let shutdown = CancellationToken::new();
+    let watch = unseen_mail.watch(&shutdown);
+    let reported = async {
+        while notifier.sent().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        shutdown.cancel();
+    };
+    tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::join!(watch, reported)
+    })
+    .await
+    .unwrap();
+    let sent = notifier.sent();
+    assert_eq!(sent[0].title, "@test can't reach the server");
+    assert_eq!(sent[0].tags, ["warning", "network"]);
+}
+
+#[tokio::test]
+async fn check_once_skips_mail_read_elsewhere() {
+    let mut read = MockMessage::new(1, "alice@example.com", "already read", "hi");
+    read.flags.push(String::from("\\Seen"));
+    let server = MockServer::start(vec![
+        read,
+        MockMessage::new(2, "bob@example.com", "unread", "hi"),
+    ])
+    .await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(account(&server), &notifier);
+    let mut session = unseen_mail.new_session().await.unwrap();
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut MailboxState::default())
+        .await
+        .unwrap();
+
+    let sent = notifier.sent();
+    assert_eq!(sent.len(), 1);
+    assert!(sent[0].message.contains("unread"));
+}
+
+#[tokio::test]
+async fn check_once_only_searches_newer_uids() {
+    let server = MockServer::start(vec![MockMessage::new(1, "a@example.com", "hi", "hi")]).await;
+    let unseen_mail = unseen_mail(account(&server), &MemoryNotifier::default());
+    let mut session = unseen_mail.new_session().await.unwrap();
+    let mut mailbox = MailboxState::default();
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut mailbox)
+        .await
+        .unwrap();
+    server.deliver(MockMessage::new(2, "b@example.com", "hi", "hi"));
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut mailbox)
+        .await
+        .unwrap();
+
+    let searches: Vec<_> = server
+        .commands()
+        .into_iter()
+        .filter(|command| command.starts_with("UID SEARCH"))
+        .collect();
+    assert_eq!(
+        searches,
+        ["UID SEARCH NEW 1:*", "UID SEARCH NEW 1:* UID 2:*"]
+    );
+    assert_eq!(mailbox.last_notified, 2);
+}
+
+#[tokio::test]
+async fn dry_run_sends_nothing() {
+    let server = MockServer::start(vec![MockMessage::new(1, "a@example.com", "hi", "hi")]).await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = UnseenMail::with_notifier(
+        account(&server),
+        Box::new(notifier.clone()),
+        Arc::new(StateStore::load(None)),
+        Arc::new(Metrics::default()),
+        None,
+        None,
+        true,
+    );
+    let mut session = unseen_mail.new_session().await.unwrap();
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut MailboxState::default())
+        .await
+        .unwrap();
+    assert!(notifier.sent().is_empty());
+}
+
+#[tokio::test]
+async fn dry_run_leaves_the_state_file_alone() {
+    let path = std::env::temp_dir().join(format!("unseenmail-dry-{}.json", std::process::id()));
+    std::fs::remove_file(&path).ok();
+    let server = MockServer::start(vec![MockMessage::new(1, "a@example.com", "hi", "hi")]).await;
+    let notifier = MemoryNotifier::default();
+    let run = |dry_run| {
+        UnseenMail::with_notifier(
+            account(&server),
+            Box::new(notifier.clone()),
+            Arc::new(StateStore::load(Some(path.clone()))),
+            Arc::new(Metrics::default()),
+            None,
+            None,
+            dry_run,
+        )
+        .run_once()
+    };
+    run(true).await.unwrap();
+    assert!(notifier.sent().is_empty());
+    run(false).await.unwrap();
+
+    let sent = notifier.sent();
+    assert_eq!(sent.len(), 1);
+    assert!(sent[0].message.contains("From: a@example.com"));
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn title_template_fills_placeholders() {
+    let server = MockServer::start(vec![MockMessage::new(
+        1,
+        "alice@example.com",
+        "{account} hello",
+        "hi",
+    )])
+    .await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(
+        account_with(
+            &server,
+            r#"title_template = "{from} in {folder}: {subject} ({count}, {unknown})""#,
+        ),
+        &notifier,
+    );
+    let mut session = unseen_mail.new_session().await.unwrap();
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut MailboxState::default())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        notifier.sent()[0].title,
+        "alice@example.com in INBOX: {account} hello (1, {unknown})"
+    );
+}
+
+#[test]
+fn title_template_rejects_unknown_placeholders() {
+    let config = Config::from_toml(
+        r#"
+        [[accounts]]
+        name = "test"
+        server = "imap.example.com"
+        port = 993
+        username = "user"
+        password = "password"
+        ntfy_url = "https://ntfy.sh"
+        ntfy_topic = "test"
+        title_template = "{account}: {sender}"
+        "#,
+    )
+    .unwrap();
+    let error = config.validate().unwrap_err().to_string();
+    assert!(
+        error.contains("unknown placeholder `{sender}`"),
+        "{}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn tag_attachments_tags_mail_with_attachments() {
+    let mut with_attachment = MockMessage::new(1, "alice@example.com", "report", "see attached");
+    with_attachment.bodystructure = String::from(
+        r#"(("TEXT" "PLAIN" ("CHARSET" "utf-8") NIL NIL "7BIT" 14 1)("APPLICATION" "PDF" ("NAME" "report.pdf") NIL NIL "BASE64" 4096 NIL ("ATTACHMENT" ("FILENAME" "report.pdf")) NIL) "MIXED" ("BOUNDARY" "b") NIL NIL)"#,
+    );
+    let server = MockServer::start(vec![
+        with_attachment,
+        MockMessage::new(2, "bob@example.com", "plain", "no attachment"),
+    ])
+    .await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(account_with(&server, "tag_attachments = true"), &notifier);
+    let mut session = unseen_mail.new_session().await.unwrap();
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut MailboxState::default())
+        .await
+        .unwrap();
+
+    let sent = notifier.sent();
+    assert_eq!(sent[0].tags, ["paperclip"]);
+    assert!(sent[1].tags.is_empty());
+}
+
+#[tokio::test]
+async fn attachment_alert_bytes_raises_large_attachments() {
+    let attachment = |uid, size| {
+        let mut message = MockMessage::new(uid, "alice@example.com", "scan", "see attached");
+        message.bodystructure = format!(
+            r#"(("TEXT" "PLAIN" ("CHARSET" "utf-8") NIL NIL "7BIT" 14 1)("IMAGE" "PNG" ("NAME" "scan.png") NIL NIL "BASE64" {} NIL ("ATTACHMENT" ("FILENAME" "scan.png")) NIL) "MIXED" ("BOUNDARY" "b") NIL NIL)"#,
+            size
+        );
+        message
+    };
+    let server = MockServer::start(vec![attachment(1, 5_000_000), attachment(2, 1000)]).await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(
+        account_with(&server, "attachment_alert_bytes = 1000000"),
+        &notifier,
+    );
+    let mut session = unseen_mail.new_session().await.unwrap();
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut MailboxState::default())
+        .await
+        .unwrap();
+
+    let sent = notifier.sent();
+    assert_eq!(sent[0].priority, Priority::High);
+    assert_eq!(sent[0].tags, ["package"]);
+    assert_eq!(sent[1].priority, Priority::Default);
+    assert!(sent[1].tags.is_empty());
+}
+
+#[tokio::test]
+async fn max_notifications_per_check_summarizes_the_rest() {
+    let server = MockServer::start(
+        (1..=5)
+            .map(|uid| MockMessage::new(uid, "alice@example.com", &format!("#{}", uid), "hi"))
+            .collect(),
+    )
+    .await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(
+        account_with(&server, "max_notifications_per_check = 2"),
+        &notifier,
+    );
+    let mut session = unseen_mail.new_session().await.unwrap();
+    let mut mailbox = MailboxState::default();
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut mailbox)
+        .await
+        .unwrap();
+
+    let sent = notifier.sent();
+    assert_eq!(sent.len(), 3);
+    assert!(sent[0].message.contains("Subject: #4"));
+    assert!(sent[1].message.contains("Subject: #5"));
+    assert_eq!(sent[2].title, "@test has 3 more new messages in INBOX");
+    assert_eq!(mailbox.last_notified, 5);
+}
+
+#[tokio::test]
+async fn folder_overrides_topic_and_title() {
+    let server = MockServer::start(vec![MockMessage::new(
+        1,
+        "boss@example.com",
+        "report",
+        "hi",
+    )])
+    .await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(
+        account_with(
+            &server,
+            r#"folders = ["INBOX", { name = "Work", ntfy_topic = "work-mail", title_template = "work: {subject}" }]"#,
+        ),
+        &notifier,
+    );
+    let mut session = unseen_mail.new_session().await.unwrap();
+    unseen_mail
+        .check_once(&mut session, "Work", &mut MailboxState::default())
+        .await
+        .unwrap();
+    // the mock has a single mailbox, a new message stands in for the INBOX one
+    server.deliver(MockMessage::new(2, "alice@example.com", "lunch", "hi"));
+    let mut inbox = MailboxState {
+        last_notified: 1,
+        uid_validity: Some(1),
+        ..MailboxState::default()
+    };
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut inbox)
+        .await
+        .unwrap();
+
+    let sent = notifier.sent();
+    assert_eq!(sent[0].topic.as_deref(), Some("work-mail"));
+    assert_eq!(sent[0].title, "work: report");
+    assert_eq!(sent[1].topic, None);
+    assert_eq!(sent[1].title, "@test has new mail in INBOX");
+}
+
+#[tokio::test]
+async fn address_family_skips_other_addresses() {
+    let server = MockServer::start(Vec::new()).await;
+    let notifier = MemoryNotifier::default();
+    let ipv4 = unseen_mail(
+        account_with(&server, r#"address_family = "ipv4""#),
+        &notifier,
+    );
+    ipv4.new_session().await.unwrap();
+
+    let ipv6 = unseen_mail(
+        account_with(&server, r#"address_family = "ipv6""#),
+        &notifier,
+    );
+    let error = ipv6.new_session().await.err().unwrap().to_string();
+    assert_eq!(error, "127.0.0.1 has no IPv6 address");
+}
+
+#[tokio::test]
+async fn poll_wait_sends_noop_with_keepalive() {
+    let server = MockServer::start(Vec::new()).await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(account_with(&server, "keepalive_secs = 1"), &notifier);
+    let mut session = unseen_mail.new_session().await.unwrap();
+
+    unseen_mail
+        .poll_wait(
+            &mut session,
+            Duration::from_millis(1500),
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+    let noops = server
+        .commands()
+        .iter()
+        .filter(|command| *command == "NOOP")
+        .count();
+    assert_eq!(noops, 1);
+}
+
+#[test]
+fn sd_notify_sends_to_the_socket() {
+    let path = std::env::temp_dir().join(format!("unseenmail-notify-{}", std::process::id()));
+    std::fs::remove_file(&path).ok();
+    let systemd = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+    SdNotify::new(path.to_str().unwrap())
+        .unwrap()
+        .notify("READY=1");
+
+    let mut buf = [0; 64];
+    let len = systemd.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"READY=1");
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn new_session_waits_for_a_connection_slot() {
+    let server = MockServer::start(Vec::new()).await;
+    let connections = Arc::new(Semaphore::new(1));
+    let unseen_mail = UnseenMail::with_notifier(
+        account(&server),
+        Box::new(MemoryNotifier::default()),
+        Arc::new(StateStore::load(None)),
+        Arc::new(Metrics::default()),
+        None,
+        Some(connections.clone()),
+        false,
+    );
+
+    let other_account = connections.acquire().await.unwrap();
+    let waiting = tokio::time::timeout(Duration::from_millis(200), unseen_mail.new_session()).await;
+    assert!(waiting.is_err());
+    assert!(server.commands().is_empty());
+    drop(other_account);
+    unseen_mail.new_session().await.unwrap();
+}
+
+#[test]
+fn json_config_matches_toml() {
+    let config = Config::from_json(
+        r#"{
+            "defaults": { "port": 993, "ntfy_url": "https://ntfy.example.com" },
+            "accounts": [{
+                "name": "json",
+                "server": "imap.example.com",
+                "username": "user",
+                "password": "password",
+                "ntfy_topic": "mail",
+                "folders": ["INBOX", { "name": "Work", "ntfy_topic": "work" }]
+            }]
+        }"#,
+    )
+    .unwrap();
+    config.validate().unwrap();
+    let account = &config.accounts[0];
+    assert_eq!(account.port, 993);
+    assert_eq!(
+        account.ntfy_url.as_deref(),
+        Some("https://ntfy.example.com")
+    );
+    assert_eq!(
+        account.folder("Work").unwrap().ntfy_topic.as_deref(),
+        Some("work")
+    );
+}
+
+#[tokio::test]
+async fn list_folders_indents_by_delimiter() {
+    let server = MockServer::start(Vec::new()).await;
+    let unseen_mail = unseen_mail(account(&server), &MemoryNotifier::default());
+    let lines = unseen_mail.list_folders().await.unwrap();
+    assert_eq!(
+        lines,
+        [
+            r#"hierarchy delimiter ".""#,
+            r"Archive (\Noselect \HasChildren)",
+            r"  Archive.2024 (\HasNoChildren)",
+            r"INBOX (\HasChildren)",
+            r"  INBOX.Sent (\HasNoChildren \Sent)",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn auth_mechanism_plain_authenticates() {
+    let server = MockServer::start(Vec::new()).await;
+    let unseen_mail = unseen_mail(
+        account_with(&server, r#"auth_mechanism = "plain""#),
+        &MemoryNotifier::default(),
+    );
+    unseen_mail.new_session().await.unwrap();
+
+    let commands = server.commands();
+    assert_eq!(commands[..2], ["CAPABILITY", "AUTHENTICATE PLAIN"]);
+    let response = STANDARD.decode(&commands[2]).unwrap();
+    assert_eq!(response, b"\0user\0password");
+    assert!(!commands.iter().any(|command| command.starts_with("LOGIN")));
+}
+
+#[tokio::test]
+async fn max_notifications_per_minute_holds_back_the_rest() {
+    let server = MockServer::start(
+        (1..=3)
+            .map(|uid| MockMessage::new(uid, "list@example.com", &format!("#{}", uid), "hi"))
+            .collect(),
+    )
+    .await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(
+        account_with(&server, "max_notifications_per_minute = 1"),
+        &notifier,
+    );
+    let mut session = unseen_mail.new_session().await.unwrap();
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut MailboxState::default())
+        .await
+        .unwrap();
+    assert_eq!(notifier.sent().len(), 1);
+
+    // shutting down sends what was held back right away
+    let shutdown = CancellationToken::new();
+    shutdown.cancel();
+    unseen_mail.flush_rate_limited(&shutdown).await;
+    let sent = notifier.sent();
+    assert_eq!(sent.len(), 2);
+    assert_eq!(sent[1].title, "@test has 2 more messages (rate limited)");
+}
+
+#[test]
+fn token_bucket_refills_over_a_minute() {
+    let start = tokio::time::Instant::now();
+    let mut bucket = TokenBucket::per_minute(2, start);
+    assert!(bucket.try_take(start));
+    assert!(bucket.try_take(start));
+    assert!(!bucket.try_take(start));
+    assert_eq!(bucket.until_available(start), Duration::from_secs(30));
+    assert!(bucket.try_take(start + Duration::from_secs(30)));
+}
+
+#[tokio::test]
+async fn notified_keyword_marks_and_excludes_notified_mail() {
+    let server = MockServer::start(vec![MockMessage::new(1, "a@example.com", "hi", "hi")]).await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(
+        account_with(&server, r#"notified_keyword = "$Notified""#),
+        &notifier,
+    );
+    let mut session = unseen_mail.new_session().await.unwrap();
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut MailboxState::default())
+        .await
+        .unwrap();
+
+    assert_eq!(notifier.sent().len(), 1);
+    assert_eq!(server.flags(1), ["$Notified"]);
+    assert!(server
+        .commands()
+        .contains(&String::from("UID SEARCH NEW 1:* UNKEYWORD $Notified")));
+}
+
+#[tokio::test]
+async fn auth_mechanism_cram_md5_answers_the_challenge() {
+    let server = MockServer::start(Vec::new()).await;
+    // the example from RFC 2195, which `MockServer` uses as its challenge
+    let mut account = account_with(&server, r#"auth_mechanism = "cram-md5""#);
+    account.username = String::from("tim");
+    account.password = Some(String::from("tanstaaftanstaaf"));
+    let unseen_mail = unseen_mail(account, &MemoryNotifier::default());
+    unseen_mail.new_session().await.unwrap();
+
+    let commands = server.commands();
+    assert_eq!(commands[1], "AUTHENTICATE CRAM-MD5");
+    let response = STANDARD.decode(&commands[2]).unwrap();
+    assert_eq!(response, b"tim b913a602c7eda7a495b4e6e7334d3890");
+}
+
+#[tokio::test]
+async fn downgrade_list_mail_lowers_list_mail() {
+    let mut list_mail = MockMessage::new(1, "list@example.com", "digest", "hi");
+    list_mail.header = format!("List-Id: <dev.example.com>\r\n{}", list_mail.header);
+    let server = MockServer::start(vec![
+        list_mail,
+        MockMessage::new(2, "alice@example.com", "direct", "hi"),
+    ])
+    .await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(
+        account_with(&server, "downgrade_list_mail = true"),
+        &notifier,
+    );
+    let mut session = unseen_mail.new_session().await.unwrap();
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut MailboxState::default())
+        .await
+        .unwrap();
+
+    let sent = notifier.sent();
+    assert_eq!(sent[0].priority, Priority::Low);
+    assert_eq!(sent[1].priority, Priority::Default);
+}
+
+/// A request received by `ntfy_server`, with lowercase header names.
+struct HttpRequest {
+    /// E.g. `PUT /path HTTP/1.1`.
+    request_line: String,
+    headers: HashMap<String, String>,
+    body: String,
+}
+
+/// Accepts `count` HTTP requests and answers 500 to those publishing to the topic `down`
+/// and 200 to the rest, returning the requests.
+async fn ntfy_server(count: usize) -> (u16, task::JoinHandle<Vec<HttpRequest>>) {
+    http_server(count, |request| {
+        if request.body.contains(r#""topic":"down""#) {
+            ("500 Internal Server Error", String::new())
+        } else {
+            ("200 OK", String::new())
+        }
+    })
+    .await
+}
+
+/// Accepts `count` HTTP requests, answers each with the status and body `answer` returns and
+/// returns the requests.
+async fn http_server(
+    count: usize,
+    answer: impl Fn(&HttpRequest) -> (&'static str, String) + Send + 'static,
+) -> (u16, task::JoinHandle<Vec<HttpRequest>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let requests = task::spawn(async move {
+        let mut requests = Vec::new();
+        for _ in 0..count {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).await.unwrap();
+            let mut headers = HashMap::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).await.unwrap();
+                if let Some((name, value)) = line.split_once(':') {
+                    headers.insert(name.to_lowercase(), value.trim().to_string());
+                }
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let length = headers
+                .get("content-length")
+                .map_or(0, |length| length.parse().unwrap());
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).await.unwrap();
+            let request = HttpRequest {
+                request_line: request_line.trim_end().to_string(),
+                headers,
+                body: String::from_utf8(body).unwrap(),
+            };
+            let (status, body) = answer(&request);
+            let response = format!(
+                "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            reader.write_all(response.as_bytes()).await.unwrap();
+            requests.push(request);
+        }
+        requests
+    });
+    (port, requests)
+}
+
+#[tokio::test]
+async fn ntfy_topic_list_publishes_to_every_topic() {
+    let (port, requests) = ntfy_server(2).await;
+    let config = Config::from_toml(&format!(
+        r#"
+        [[accounts]]
+        name = "test"
+        server = "imap.example.com"
+        port = 993
+        username = "user"
+        password = "password"
+        ntfy_url = "http://127.0.0.1:{}"
+        ntfy_topic = ["down", "phone"]
+        "#,
+        port
+    ))
+    .unwrap();
+    let notifier = notifier::from_account(&config.accounts[0]).unwrap();
+    notifier
+        .notify(&Notification::new("title", "message", Priority::Default))
+        .await
+        .unwrap();
+
+    let mut topics: Vec<_> = requests
+        .await
+        .unwrap()
+        .iter()
+        .map(|request| {
+            serde_json::from_str::<serde_json::Value>(&request.body).unwrap()["topic"].clone()
+        })
+        .collect();
+    topics.sort_by_key(|topic| topic.to_string());
+    assert_eq!(topics, ["down", "phone"]);
+}
+
+#[tokio::test]
+async fn reconnect_does_not_notify_mail_found_again() {
+    // without Message-IDs only `last_notified` can tell the mail was notified
+    let messages = (1..=2)
+        .map(|uid| {
+            let mut message = MockMessage::new(uid, "alice@example.com", "hi", "hi");
+            message.header = String::from("From: alice@example.com\r\nSubject: hi\r\n\r\n");
+            message
+        })
+        .collect();
+    // like Dovecot once `\Recent` was reset, the mock finds every message again
+    let server = MockServer::start(messages).await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(account(&server), &notifier);
+    let mut session = unseen_mail.new_session().await.unwrap();
+    let mut mailboxes = unseen_mail.state.mailboxes("test");
+    unseen_mail
+        .check_folders(&mut session, &mut mailboxes)
+        .await
+        .unwrap();
+    unseen_mail.logout(session).await;
+
+    let mut session = unseen_mail.new_session().await.unwrap();
+    let mut mailboxes = unseen_mail.state.mailboxes("test");
+    unseen_mail
+        .check_folders(&mut session, &mut mailboxes)
+        .await
+        .unwrap();
+
+    assert_eq!(notifier.sent().len(), 2);
+    assert_eq!(mailboxes["INBOX"].last_notified, 2);
+}
+
+#[test]
+fn body_preview_decodes_the_declared_charset() {
+    let preview = |content_type: &str, text: &[u8]| {
+        let header = format!(
+            "Subject: hi\r\nContent-Type: {}\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+            content_type
+        );
+        crate::body_preview(header.as_bytes(), text)
+    };
+    assert_eq!(
+        preview("text/plain; charset=iso-8859-1", b"caf\xe9").as_deref(),
+        Some("café")
+    );
+    assert_eq!(
+        preview("text/plain; charset=gb2312", b"\xc4\xe3\xba\xc3").as_deref(),
+        Some("你好")
+    );
+    // undeclared UTF-8, and bytes that aren't UTF-8 either
+    assert_eq!(
+        preview("text/plain", "café".as_bytes()).as_deref(),
+        Some("café")
+    );
+    assert_eq!(
+        preview("text/plain", b"caf\xe9").as_deref(),
+        Some("caf\u{fffd}")
+    );
+}
+
+#[tokio::test]
+async fn run_once_resumes_from_the_state_file() {
+    let path = std::env::temp_dir().join(format!("unseenmail-once-{}.json", std::process::id()));
+    std::fs::remove_file(&path).ok();
+    let server = MockServer::start(vec![MockMessage::new(1, "a@example.com", "hi", "hi")]).await;
+    let notifier = MemoryNotifier::default();
+    // every run is a new process that only has the state file
+    let run = || {
+        UnseenMail::with_notifier(
+            account(&server),
+            Box::new(notifier.clone()),
+            Arc::new(StateStore::load(Some(path.clone()))),
+            Arc::new(Metrics::default()),
+            None,
+            None,
+            false,
+        )
+        .run_once()
+    };
+    run().await.unwrap();
+    server.deliver(MockMessage::new(2, "b@example.com", "hi", "hi"));
+    run().await.unwrap();
+    run().await.unwrap();
+
+    let sent = notifier.sent();
+    assert_eq!(sent.len(), 2);
+    assert!(sent[1].message.contains("From: b@example.com"));
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn condstore_skips_unchanged_folders() {
+    let server = MockServer::start(vec![MockMessage::new(1, "a@example.com", "hi", "hi")]).await;
+    server.advertise("CONDSTORE");
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(account(&server), &notifier);
+    let mut session = unseen_mail.new_session().await.unwrap();
+    let mut mailbox = MailboxState::default();
+    for _ in 0..2 {
+        unseen_mail
+            .check_once(&mut session, "INBOX", &mut mailbox)
+            .await
+            .unwrap();
+    }
+    server.deliver(MockMessage::new(2, "b@example.com", "hi", "hi"));
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut mailbox)
+        .await
+        .unwrap();
+
+    let commands = server.commands();
+    assert!(commands.contains(&String::from("SELECT \"INBOX\" (CONDSTORE)")));
+    let searches: Vec<_> = commands
+        .into_iter()
+        .filter(|command| command.starts_with("UID SEARCH"))
+        .collect();
+    assert_eq!(
+        searches,
+        ["UID SEARCH NEW 1:*", "UID SEARCH NEW 1:* UID 2:* MODSEQ 2"]
+    );
+    assert_eq!(notifier.sent().len(), 2);
+    assert_eq!(mailbox.highest_modseq, Some(2));
+}
+
+#[tokio::test]
+async fn on_notify_move_to_moves_notified_mail() {
+    for extensions in [&["MOVE"][..], &["UIDPLUS"], &[]] {
+        let server = MockServer::start(vec![
+            MockMessage::new(1, "a@example.com", "hi", "hi"),
+            MockMessage::new(2, "b@example.com", "hi", "hi"),
+        ])
+        .await;
+        for extension in extensions {
+            server.advertise(extension);
+        }
+        let unseen_mail = unseen_mail(
+            account_with(&server, r#"on_notify_move_to = "Archive""#),
+            &MemoryNotifier::default(),
+        );
+        let mut session = unseen_mail.new_session().await.unwrap();
+        unseen_mail
+            .check_once(&mut session, "INBOX", &mut MailboxState::default())
+            .await
+            .unwrap();
+
+        assert!(server.uids().is_empty(), "{:?}", extensions);
+        let commands = server.commands();
+        let filing = commands
+            .iter()
+            .skip_while(|command| !command.starts_with("UID FETCH"))
+            .skip(1);
+        let expected: &[&str] = match extensions {
+            ["MOVE"] => &["UID MOVE 1,2 \"Archive\""],
+            ["UIDPLUS"] => &[
+                "UID COPY 1,2 \"Archive\"",
+                "UID STORE 1,2 +FLAGS.SILENT (\\Deleted)",
+                "UID EXPUNGE 1,2",
+            ],
+            _ => &[
+                "UID COPY 1,2 \"Archive\"",
+                "UID STORE 1,2 +FLAGS.SILENT (\\Deleted)",
+                "EXPUNGE",
+            ],
+        };
+        assert_eq!(filing.collect::<Vec<_>>(), expected);
+    }
+}
+
+#[tokio::test]
+async fn bind_address_connects_from_that_address() {
+    let server = MockServer::start(Vec::new()).await;
+    let notifier = MemoryNotifier::default();
+    // all of 127.0.0.0/8 is local on Linux
+    let bound = unseen_mail(
+        account_with(&server, r#"bind_address = "127.0.0.2""#),
+        &notifier,
+    );
+    bound.new_session().await.unwrap();
+
+    // TEST-NET-1, not an address of this machine
+    let unbindable = unseen_mail(
+        account_with(&server, r#"bind_address = "192.0.2.1""#),
+        &notifier,
+    );
+    let error = format!("{:#}", unbindable.new_session().await.err().unwrap());
+    assert!(
+        error.starts_with("failed to bind to 192.0.2.1"),
+        "{}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn ntfy_tags_merge_account_and_rule_tags() {
+    let server = MockServer::start(vec![
+        MockMessage::new(1, "billing@example.com", "Invoice 42", "pay up"),
+        MockMessage::new(2, "bob@example.com", "lunch?", "noon"),
+    ])
+    .await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(
+        account_with(
+            &server,
+            r#"
+            ntfy_tags = ["email"]
+            [accounts.filters]
+            tag = [
+              { header = "subject", glob = "invoice*", tags = ["moneybag"] },
+              { header = "from", glob = "billing@*", tags = ["email", "receipt"] },
+            ]
+            "#,
+        ),
+        &notifier,
+    );
+    let mut session = unseen_mail.new_session().await.unwrap();
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut MailboxState::default())
+        .await
+        .unwrap();
+
+    let sent = notifier.sent();
+    assert_eq!(sent[0].tags, ["email", "moneybag", "receipt"]);
+    assert_eq!(sent[1].tags, ["email"]);
+}
+
+#[tokio::test]
+async fn vip_senders_only_notifies_vips_even_if_excluded() {
+    let server = MockServer::start(vec![
+        MockMessage::new(1, "Boss <BOSS@example.com>", "now", "now"),
+        MockMessage::new(2, "alice@example.com", "hi", "hi"),
+        MockMessage::new(3, "ci@build.example.org", "green", "green"),
+        MockMessage::new(4, "eve@notexample.org", "hi", "hi"),
+    ])
+    .await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(
+        account_with(
+            &server,
+            r#"
+            vip_senders = ["boss@example.com", "@build.example.org", "example.org"]
+            [accounts.filters]
+            exclude = [{ header = "from", glob = "ci@*" }]
+            "#,
+        ),
+        &notifier,
+    );
+    let mut session = unseen_mail.new_session().await.unwrap();
+    let mut mailbox = MailboxState::default();
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut mailbox)
+        .await
+        .unwrap();
+
+    let subjects = notifier
+        .sent()
+        .iter()
+        .map(|sent| sent.message.lines().nth(1).unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(subjects, ["Subject: now", "Subject: green"]);
+    assert_eq!(mailbox.last_notified, 4);
+}
+
+#[tokio::test]
+async fn notify_on_parse_error_reports_unparsable_mail() {
+    let mut broken = MockMessage::new(1, "alice@example.com", "hi", "hi");
+    broken.header = String::from("Subject\r\n hi\r\n\r\n");
+    let server = MockServer::start(vec![broken]).await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(
+        account_with(&server, "notify_on_parse_error = true"),
+        &notifier,
+    );
+    let mut session = unseen_mail.new_session().await.unwrap();
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut MailboxState::default())
+        .await
+        .unwrap();
+
+    let sent = notifier.sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(
+        sent[0].title,
+        "@test received mail it couldn't parse in INBOX"
+    );
+    assert!(
+        sent[0].message.starts_with("UID 1: "),
+        "{}",
+        sent[0].message
+    );
+    assert_eq!(sent[0].priority, Priority::Low);
+}
+
+#[tokio::test]
+async fn ntfy_cache_and_firebase_can_be_turned_off() {
+    let (port, requests) = ntfy_server(1).await;
+    let config = Config::from_toml(&format!(
+        r#"
+        [[accounts]]
+        name = "test"
+        server = "imap.example.com"
+        port = 993
+        username = "user"
+        password = "password"
+        ntfy_url = "http://127.0.0.1:{}"
+        ntfy_topic = "phone"
+        ntfy_cache = false
+        ntfy_firebase = false
+        "#,
+        port
+    ))
+    .unwrap();
+    let notifier = notifier::from_account(&config.accounts[0]).unwrap();
+    notifier
+        .notify(&Notification::new("title", "message", Priority::Max))
+        .await
+        .unwrap();
+
+    let requests = requests.await.unwrap();
+    assert_eq!(requests[0].headers["cache"], "no");
+    assert_eq!(requests[0].headers["firebase"], "no");
+}
+
+#[tokio::test]
+async fn failed_notifications_are_queued_until_the_notifier_works() {
+    let path = std::env::temp_dir().join(format!("unseenmail-queue-{}.jsonl", std::process::id()));
+    std::fs::remove_file(&path).ok();
+    let server = MockServer::start(Vec::new()).await;
+    let extra = format!("notify_attempts = 2\nnotify_queue_file = {:?}", path);
+    let notifier = MemoryNotifier::default();
+    notifier.fail_next(2);
+    let first = unseen_mail(account_with(&server, &extra), &notifier);
+    assert!(first.report_error("first").await.is_err());
+    assert!(notifier.sent().is_empty());
+    assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+
+    // a restart only has the queue file
+    let restarted = unseen_mail(account_with(&server, &extra), &notifier);
+    restarted.report_error("second").await.unwrap();
+    let sent = notifier.sent();
+    assert_eq!(sent.len(), 2);
+    assert_eq!(sent[0].message, "first");
+    assert_eq!(sent[1].message, "second");
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+    std::fs::remove_file(&path).ok();
+}
+
+#[test]
+fn notify_queue_size_drops_the_oldest() {
+    use std::os::unix::fs::PermissionsExt;
+    let path = std::env::temp_dir().join(format!("unseenmail-full-{}.jsonl", std::process::id()));
+    std::fs::remove_file(&path).ok();
+    let queue = NotificationQueue::open(Some(path.clone()), 2);
+    for message in ["1", "2", "3"] {
+        queue.push(Notification::new("title", message, Priority::Default));
+    }
+    assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+    assert_eq!(
+        std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+        0o600
+    );
+
+    // a smaller queue after a restart keeps the newest
+    let queue = NotificationQueue::open(Some(path.clone()), 1);
+    let messages = queue
+        .take()
+        .into_iter()
+        .map(|notification| notification.message)
+        .collect::<Vec<_>>();
+    assert_eq!(messages, ["3"]);
+    assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn queued_action_buttons_keep_their_token_out_of_the_file() {
+    let path = std::env::temp_dir().join(format!("unseenmail-token-{}.jsonl", std::process::id()));
+    std::fs::remove_file(&path).ok();
+    let server = MockServer::start(vec![MockMessage::new(1, "a@example.com", "hi", "hi")]).await;
+    let extra = format!("notify_attempts = 1\nnotify_queue_file = {:?}", path);
+    let notifier = MemoryNotifier::default();
+    notifier.fail_next(1);
+    let actions = Arc::new(Actions::new(ActionsConfig {
+        bind: "127.0.0.1:0".parse().unwrap(),
+        url: String::from("https://unseenmail.example.com"),
+        token: String::from("s3cret"),
+    }));
+    let run = || {
+        UnseenMail::with_notifier(
+            account_with(&server, &extra),
+            Box::new(notifier.clone()),
+            Arc::new(StateStore::load(None)),
+            Arc::new(Metrics::default()),
+            Some(actions.clone()),
+            None,
+            false,
+        )
+    };
+    let first = run();
+    let mut session = first.new_session().await.unwrap();
+    first
+        .check_once(&mut session, "INBOX", &mut MailboxState::default())
+        .await
+        .ok();
+    let queued = std::fs::read_to_string(&path).unwrap();
+    assert!(queued.contains("Mark as read"));
+    assert!(!queued.contains("s3cret"));
+
+    // after a restart the button gets its token back
+    run().report_error("back").await.unwrap();
+    let sent = notifier.sent();
+    assert_eq!(
+        sent[0].actions[0].headers,
+        [(String::from("Authorization"), String::from("Bearer s3cret"))]
+    );
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn compress_deflates_the_connection_when_offered() {
+    let server = MockServer::start(vec![MockMessage::new(1, "a@example.com", "hi", "hi")]).await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(account_with(&server, "compress = true"), &notifier);
+    // not offered, so nothing changes
+    unseen_mail.new_session().await.unwrap();
+    assert!(!server
+        .commands()
+        .contains(&String::from("COMPRESS DEFLATE")));
+
+    server.advertise("COMPRESS=DEFLATE");
+    let mut session = unseen_mail.new_session().await.unwrap();
+    assert!(server
+        .commands()
+        .contains(&String::from("COMPRESS DEFLATE")));
+    let mut mailbox = MailboxState::default();
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut mailbox)
+        .await
+        .unwrap();
+    assert_eq!(notifier.sent().len(), 1);
+    assert!(server
+        .commands()
+        .contains(&String::from("UID SEARCH NEW 1:*")));
+}
+
+#[test]
+fn startup_stagger_ms_delays_each_account_a_bit_more() {
+    let config = Config::from_toml(
+        r#"
+        startup_stagger_ms = 500
+
+        [[accounts]]
+        name = "test"
+        server = "imap.example.com"
+        port = 993
+        username = "user"
+        password = "password"
+        ntfy_url = "https://ntfy.example.com"
+        ntfy_topic = "mail"
+        "#,
+    )
+    .unwrap();
+    let delays: Vec<_> = (0..3)
+        .map(|i| Config::startup_delay(config.startup_stagger_ms, i).as_millis())
+        .collect();
+    assert_eq!(delays, [0, 500, 1000]);
+    assert!(Config::startup_delay(None, 5).is_zero());
+}
+
+#[tokio::test]
+async fn rejected_login_is_reported_right_away() {
+    let server = MockServer::start(Vec::new()).await;
+    server.reject_logins();
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(account(&server), &notifier);
+
+    let shutdown = CancellationToken::new();
+    let watch = unseen_mail.watch(&shutdown);
+    let reported = async {
+        while notifier.sent().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        shutdown.cancel();
+    };
+    tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::join!(watch, reported)
+    })
+    .await
+    .unwrap();
+    let sent = notifier.sent();
+    assert_eq!(sent[0].title, "@test login rejected");
+    assert_eq!(sent[0].tags, ["warning", "auth"]);
+    assert_eq!(sent[0].priority, Priority::High);
+    assert!(
+        sent[0].message.contains("trying again after 1800s"),
+        "{}",
+        sent[0].message
+    );
+}
+
+#[tokio::test]
+async fn stop_on_auth_failure_stops_watching() {
+    let server = MockServer::start(Vec::new()).await;
+    server.reject_logins();
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(
+        account_with(&server, "stop_on_auth_failure = true"),
+        &notifier,
+    );
+    tokio::time::timeout(
+        Duration::from_secs(5),
+        unseen_mail.watch(&CancellationToken::new()),
+    )
+    .await
+    .unwrap();
+
+    let sent = notifier.sent();
+    assert_eq!(sent.len(), 1);
+    assert!(
+        sent[0].message.contains("not trying again"),
+        "{}",
+        sent[0].message
+    );
+    let logins = server
+        .commands()
+        .iter()
+        .filter(|command| command.starts_with("LOGIN"))
+        .count();
+    assert_eq!(logins, 1);
+}
+
+#[test]
+fn config_template_covers_every_key_and_parses() {
+    use crate::config::{account_keys, config_keys, ACCOUNT_KEY_DOCS, CONFIG_KEY_DOCS};
+    assert!(account_keys().contains(&"notify_queue_size"));
+    assert!(config_keys().contains(&"startup_stagger_ms"));
+    for (keys, docs) in [
+        (config_keys(), CONFIG_KEY_DOCS),
+        (account_keys(), ACCOUNT_KEY_DOCS),
+    ] {
+        let keys = keys.iter().filter(|key| **key != "accounts");
+        for key in keys.clone() {
+            let doc = docs.iter().find(|doc| doc.key == *key);
+            assert!(
+                doc.is_some_and(|doc| !doc.description.is_empty()),
+                "`{}` has no description",
+                key
+            );
+        }
+        for doc in docs {
+            assert!(
+                keys.clone().any(|key| *key == doc.key),
+                "`{}` is not a key",
+                doc.key
+            );
+        }
+    }
+    let template = crate::template::config_template();
+    // every commented-out line still parses once it is uncommented
+    let uncommented = template
+        .lines()
+        .map(|line| line.strip_prefix("# ").unwrap_or(line))
+        .filter(|line| !line.starts_with("unseenmail config") && !line.starts_with("Optional keys"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    toml::from_str::<toml::Table>(&uncommented).unwrap();
+    let config = Config::from_toml(&template).unwrap();
+    config.validate().unwrap();
+    assert_eq!(config.accounts[0].name, "example");
+}
+
+#[tokio::test]
+async fn display_timezone_converts_the_date() {
+    let mut dated = MockMessage::new(1, "a@example.com", "hi", "hi");
+    dated.header = format!(
+        "Date: Tue, 2 Jan 2024 06:04:00 +0000 (UTC)\r\n{}",
+        dated.header
+    );
+    let mut undated = MockMessage::new(2, "b@example.com", "hi", "hi");
+    undated.header = format!("Date: sometime last week\r\n{}", undated.header);
+    let server = MockServer::start(vec![dated, undated]).await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(
+        account_with(&server, "display_timezone = \"Asia/Tokyo\""),
+        &notifier,
+    );
+    let mut session = unseen_mail.new_session().await.unwrap();
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut MailboxState::default())
+        .await
+        .unwrap();
+
+    let sent = notifier.sent();
+    assert!(
+        sent[0].message.contains("Date: 2024-01-02 15:04\n"),
+        "{}",
+        sent[0].message
+    );
+    assert!(
+        sent[1].message.contains("Date: sometime last week\n"),
+        "{}",
+        sent[1].message
+    );
+
+    let error = Config::from_toml(
+        r#"
+        [[accounts]]
+        name = "test"
+        server = "imap.example.com"
+        port = 993
+        username = "user"
+        password = "password"
+        ntfy_url = "https://ntfy.example.com"
+        ntfy_topic = "mail"
+        display_timezone = "Mars/Olympus"
+        "#,
+    )
+    .err()
+    .unwrap();
+    assert!(format!("{:#}", error).contains("unknown timezone `Mars/Olympus`"));
+}
+
+#[test]
+fn config_dir_reads_every_toml_file_in_order() {
+    let dir = std::env::temp_dir().join(format!("unseenmail-config-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let account = |name: &str| {
+        format!(
+            r#"
+            [[accounts]]
+            name = "{}"
+            server = "imap.example.com"
+            port = 993
+            username = "user"
+            password = "password"
+            ntfy_url = "https://ntfy.example.com"
+            ntfy_topic = "mail"
+            "#,
+            name
+        )
+    };
+    std::fs::write(dir.join("b.toml"), account("second")).unwrap();
+    std::fs::write(dir.join("a.toml"), account("first")).unwrap();
+    std::fs::write(dir.join(".c.toml"), account("hidden")).unwrap();
+    std::fs::write(dir.join("d.toml.bak"), account("backup")).unwrap();
+    let main = std::env::temp_dir().join(format!("unseenmail-main-{}.toml", std::process::id()));
+    std::fs::write(&main, account("main")).unwrap();
+
+    let config = Config::load(std::slice::from_ref(&main), Some(&dir)).unwrap();
+    let names = config
+        .accounts
+        .iter()
+        .map(|account| account.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["main", "first", "second"]);
+
+    std::fs::write(dir.join("c.toml"), account("first")).unwrap();
+    let error = Config::load(&[], Some(&dir)).err().unwrap();
+    let error = format!("{:#}", error);
+    std::fs::remove_dir_all(&dir).unwrap();
+    std::fs::remove_file(&main).unwrap();
+    assert!(error.contains("account [first] is in both config file"));
+    assert!(error.contains("a.toml and file") && error.ends_with("c.toml"));
+}
+
+#[tokio::test]
+async fn recipient_in_notification_lists_the_delivered_alias_first() {
+    let mut mail = MockMessage::new(1, "alice@example.com", "hi", "hi");
+    mail.header = String::from(concat!(
+        "Delivered-To: <Shop@example.com>\r\n",
+        "From: alice@example.com\r\n",
+        "To: =?utf-8?q?J=C3=BCrgen?= <juergen@example.com>, team: a@example.com, b@example.com;\r\n",
+        "Cc: c@example.com, Shop Alias <shop@example.com>\r\n",
+        "Subject: hi\r\n\r\n",
+    ));
+    let server = MockServer::start(vec![mail]).await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(
+        account_with(&server, "recipient_in_notification = true"),
+        &notifier,
+    );
+    let mut session = unseen_mail.new_session().await.unwrap();
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut MailboxState::default())
+        .await
+        .unwrap();
+
+    let sent = notifier.sent();
+    let to =
+        "Shop Alias <shop@example.com>, Jürgen <juergen@example.com>, a@example.com and 2 more";
+    assert_eq!(
+        sent[0].message,
+        format!("From: alice@example.com\nTo: {}\nSubject: hi\n\nhi", to)
+    );
+    assert_eq!(sent[0].mail.as_ref().unwrap().to.as_deref(), Some(to));
+}
+
+#[tokio::test]
+async fn poll_interval_defaults_to_idle_timeout() {
+    let server = MockServer::start(Vec::new()).await;
+    let account = account_with(&server, "idle_timeout_secs = 42");
+    assert_eq!(account.poll_interval(), Duration::from_secs(42));
+    let account = account_with(&server, "idle_timeout_secs = 42\npoll_interval_secs = 7");
+    assert_eq!(account.poll_interval(), Duration::from_secs(7));
+}
+
+/// `Filters::allows` for a message from `from` with `subject`.
+fn filter_allows(filters: &str, from: &str, subject: &str) -> bool {
+    let filters: Filters = toml::from_str(filters).unwrap();
+    let header = format!(
+        "From: {}\r\nSubject: {}\r\nTo: me@example.com\r\n\r\n",
+        from, subject
+    );
+    let (headers, _) = mailparse::parse_headers(header.as_bytes()).unwrap();
+    filters.allows(&headers)
+}
+
+#[test]
+fn filters_exclude_wins_over_include() {
+    let filters = r#"
+        include = [{ header = "to", glob = "me@*" }]
+        exclude = [{ header = "from", glob = "*@spam.example.com" }]
+    "#;
+    assert!(filter_allows(filters, "Alice <alice@example.com>", "hi"));
+    assert!(!filter_allows(filters, "Bot <bot@spam.example.com>", "hi"));
+    // without a matching include rule nothing is notified
+    let filters = r#"include = [{ header = "subject", glob = "urgent*" }]"#;
+    assert!(filter_allows(
+        filters,
+        "alice@example.com",
+        "Urgent: call me"
+    ));
+    assert!(!filter_allows(filters, "alice@example.com", "lunch?"));
+    // and without any rules everything is
+    assert!(filter_allows("", "alice@example.com", "lunch?"));
+}
+
+#[test]
+fn filters_match_globs_and_regexes_with_or_without_case() {
+    // a glob has to match the whole value, or a whole bare address
+    let glob = r#"exclude = [{ header = "from", glob = "alice@*" }]"#;
+    assert!(!filter_allows(glob, "Alice <ALICE@example.com>", "hi"));
+    assert!(filter_allows(glob, "malice@example.com", "hi"));
+    // a regex matches anywhere unless anchored
+    let regex = r#"exclude = [{ header = "subject", regex = "\\[ci\\]" }]"#;
+    assert!(!filter_allows(regex, "a@example.com", "build [CI] failed"));
+    let case_sensitive = r#"
+        case_sensitive = true
+        exclude = [{ header = "subject", regex = "\\[ci\\]" }]
+    "#;
+    assert!(filter_allows(
+        case_sensitive,
+        "a@example.com",
+        "build [CI] failed"
+    ));
+    // a rule can override the table
+    let rule_override = r#"
+        case_sensitive = true
+        exclude = [{ header = "subject", regex = "\\[ci\\]", case_sensitive = false }]
+    "#;
+    assert!(!filter_allows(
+        rule_override,
+        "a@example.com",
+        "build [CI] failed"
+    ));
+
+    let both = r#"exclude = [{ header = "from", glob = "a*", regex = "a" }]"#;
+    assert!(toml::from_str::<Filters>(both).is_err());
+}
+
+#[test]
+fn defaults_fill_in_what_an_account_leaves_out() {
+    let config = Config::from_toml(
+        r#"
+        [defaults]
+        port = 993
+        ntfy_url = "https://ntfy.example.com"
+        ntfy_priority = "high"
+        filters = { exclude = [{ header = "from", glob = "*@spam.example.com" }] }
+
+        [[accounts]]
+        name = "inherits"
+        server = "imap.example.com"
+        username = "user"
+        password = "password"
+        ntfy_topic = "mail"
+
+        [[accounts]]
+        name = "overrides"
+        server = "imap.example.com"
+        port = 143
+        username = "user"
+        password = "password"
+        ntfy_topic = "mail"
+        ntfy_priority = "low"
+        [accounts.filters]
+        include = [{ header = "to", glob = "me@*" }]
+        "#,
+    )
+    .unwrap();
+    config.validate().unwrap();
+    let (inherits, overrides) = (&config.accounts[0], &config.accounts[1]);
+    assert_eq!(inherits.port, 993);
+    assert_eq!(inherits.ntfy_priority, Priority::High);
+    assert_eq!(
+        inherits.ntfy_url.as_deref(),
+        Some("https://ntfy.example.com")
+    );
+    assert_eq!(overrides.port, 143);
+    assert_eq!(overrides.ntfy_priority, Priority::Low);
+    assert_eq!(
+        overrides.ntfy_url.as_deref(),
+        Some("https://ntfy.example.com")
+    );
+
+    // a table from the account replaces the default one instead of being merged into it
+    let header = "From: bot@spam.example.com\r\nTo: me@example.com\r\n\r\n";
+    let (headers, _) = mailparse::parse_headers(header.as_bytes()).unwrap();
+    assert!(!inherits.filters.allows(&headers));
+    assert!(overrides.filters.allows(&headers));
+}
+
+#[test]
+fn validate_reports_every_problem_at_once() {
+    let config = Config::from_toml(
+        r#"
+        [defaults]
+        password = "password"
+        ntfy_url = "https://ntfy.example.com"
+        ntfy_topic = "mail"
+
+        [[accounts]]
+        name = "twice"
+        server = ""
+        port = 0
+        username = "user"
+
+        [[accounts]]
+        name = "twice"
+        server = "imap.example.com"
+        port = 993
+        username = ""
+        "#,
+    )
+    .unwrap();
+    let error = config.validate().unwrap_err().to_string();
+    assert_eq!(
+        error,
+        "invalid config:\n  \
+         - [twice] `server` is empty\n  \
+         - [twice] `port` must not be 0\n  \
+         - duplicate account name [twice]\n  \
+         - [twice] `username` is empty"
+    );
+
+    let error = Config::from_toml("accounts = []")
+        .unwrap()
+        .validate()
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "invalid config:\n  - no accounts configured"
+    );
+}
+
+/// Sends `request` to `port` and returns whatever comes back before the connection closes.
+async fn http_exchange(port: u16, request: &[u8]) -> String {
+    let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .unwrap();
+    // the server may hang up before reading everything
+    stream.write_all(request).await.ok();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.ok();
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+#[tokio::test]
+async fn action_endpoint_rejects_wrong_tokens_and_oversized_requests() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let actions = Arc::new(Actions::new(ActionsConfig {
+        bind: "127.0.0.1:0".parse().unwrap(),
+        url: String::from("https://unseenmail.example.com"),
+        token: String::from("s3cret"),
+    }));
+    task::spawn(crate::actions::serve(listener, actions));
+
+    for token in ["", "Bearer s3cre", "Bearer s3creT", "Bearer s3cret!"] {
+        let request = format!(
+            "POST /seen?account=test&folder=INBOX&uid=1 HTTP/1.1\r\nAuthorization: {}\r\n\r\n",
+            token
+        );
+        let response = http_exchange(port, request.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+    }
+    let request = "POST /seen?account=test&folder=INBOX&uid=1 HTTP/1.1\r\n\
+                   Authorization: Bearer s3cret\r\n\r\n";
+    let response = http_exchange(port, request.as_bytes()).await;
+    assert!(!response.starts_with("HTTP/1.1 401"), "{}", response);
+
+    // neither an endless request line nor endless headers get an answer
+    let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(16 * 1024));
+    assert_eq!(http_exchange(port, long_line.as_bytes()).await, "");
+    let many_headers = format!("GET / HTTP/1.1\r\n{}\r\n", "X-Filler: 1\r\n".repeat(100));
+    assert_eq!(http_exchange(port, many_headers.as_bytes()).await, "");
+}
+
+#[tokio::test]
+async fn test_notify_sends_one_notification_per_account() {
+    let (port, requests) = ntfy_server(2).await;
+    let config = Config::from_toml(&format!(
+        r#"
+        [defaults]
+        server = "imap.example.com"
+        port = 993
+        username = "user"
+        password = "password"
+        ntfy_url = "http://127.0.0.1:{}"
+
+        [[accounts]]
+        name = "work"
+        ntfy_topic = "phone"
+
+        [[accounts]]
+        name = "home"
+        ntfy_topic = "down"
+        "#,
+        port
+    ))
+    .unwrap();
+    let error = crate::test_notify(&config).await.unwrap_err();
+    assert_eq!(error.to_string(), "1 of 2 test notifications failed");
+
+    let mut titles: Vec<_> = requests
+        .await
+        .unwrap()
+        .iter()
+        .map(|request| {
+            serde_json::from_str::<serde_json::Value>(&request.body).unwrap()["title"].clone()
+        })
+        .collect();
+    titles.sort_by_key(|title| title.to_string());
+    assert_eq!(
+        titles,
+        ["@home test notification", "@work test notification"]
+    );
+}
+
+#[test]
+fn disabled_accounts_are_skipped_before_validation() {
+    let mut config = Config::from_toml(
+        r#"
+        [defaults]
+        server = "imap.example.com"
+        port = 993
+        username = "user"
+        password = "password"
+        ntfy_url = "https://ntfy.example.com"
+        ntfy_topic = "mail"
+
+        [[accounts]]
+        name = "work"
+
+        [[accounts]]
+        name = "old"
+        enabled = false
+        server = ""
+        "#,
+    )
+    .unwrap();
+    assert!(config.validate().is_err());
+    crate::skip_disabled(&mut config);
+    let names: Vec<_> = config
+        .accounts
+        .iter()
+        .map(|account| account.name.as_str())
+        .collect();
+    assert_eq!(names, ["work"]);
+    config.validate().unwrap();
+}
+
+#[test]
+fn config_falls_back_to_the_environment_without_a_path() {
+    let account = |name: &str| {
+        format!(
+            r#"
+            [[accounts]]
+            name = "{}"
+            server = "imap.example.com"
+            port = 993
+            username = "user"
+            password = "password"
+            ntfy_url = "https://ntfy.example.com"
+            ntfy_topic = "mail"
+            "#,
+            name
+        )
+    };
+    let file = std::env::temp_dir().join(format!("unseenmail-env-{}.toml", std::process::id()));
+    std::fs::write(&file, account("file")).unwrap();
+    // no other test reads the variable
+    std::env::set_var("UNSEENMAIL_CONFIG", account("env"));
+    let from_env = Config::load(&[], None).unwrap();
+    let from_file = Config::load(std::slice::from_ref(&file), None).unwrap();
+    std::env::remove_var("UNSEENMAIL_CONFIG");
+    let missing = Config::load(&[], None).err().unwrap();
+    std::fs::remove_file(&file).unwrap();
+
+    assert_eq!(from_env.accounts[0].name, "env");
+    // a path always wins over the environment
+    assert_eq!(from_file.accounts.len(), 1);
+    assert_eq!(from_file.accounts[0].name, "file");
+    assert_eq!(
+        missing.to_string(),
+        "no config given, pass --config or --config-dir or set UNSEENMAIL_CONFIG"
+    );
+}
+
+#[tokio::test]
+async fn shutdown_sends_held_back_mail_and_logs_out_in_time() {
+    let server = MockServer::start(
+        (1..=3)
+            .map(|uid| MockMessage::new(uid, "list@example.com", &format!("#{}", uid), "hi"))
+            .collect(),
+    )
+    .await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(
+        account_with(&server, "max_notifications_per_minute = 1"),
+        &notifier,
+    );
+    let shutdown = CancellationToken::new();
+    let run = unseen_mail.run(shutdown.clone());
+    let stop = async {
+        while notifier.sent().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        shutdown.cancel();
+    };
+    let config = Config::from_toml("accounts = []").unwrap();
+    tokio::time::timeout(config.shutdown_timeout(), async { tokio::join!(run, stop) })
+        .await
+        .unwrap();
+
+    let titles: Vec<_> = notifier
+        .sent()
+        .iter()
+        .map(|notification| notification.title.clone())
+        .collect();
+    assert_eq!(titles.len(), 2);
+    assert_eq!(titles[1], "@test has 2 more messages (rate limited)");
+    assert!(server.commands().contains(&String::from("LOGOUT")));
+
+    let config = Config::from_toml("shutdown_timeout_secs = 3\naccounts = []").unwrap();
+    assert_eq!(config.shutdown_timeout(), Duration::from_secs(3));
+}
+
+#[test]
+fn account_option_keeps_only_the_named_accounts() {
+    let config = || {
+        Config::from_toml(
+            r#"
+            [defaults]
+            server = "imap.example.com"
+            port = 993
+            username = "user"
+            password = "password"
+            ntfy_url = "https://ntfy.example.com"
+            ntfy_topic = "mail"
+
+            [[accounts]]
+            name = "work"
+
+            [[accounts]]
+            name = "home"
+
+            [[accounts]]
+            name = "lists"
+            "#,
+        )
+        .unwrap()
+    };
+    let names = |config: &Config| {
+        config
+            .accounts
+            .iter()
+            .map(|account| account.name.clone())
+            .collect::<Vec<_>>()
+    };
+    let mut all = config();
+    crate::only_accounts(&mut all, &[]).unwrap();
+    assert_eq!(names(&all), ["work", "home", "lists"]);
+
+    // the config's order is kept, not the order of the options
+    let mut some = config();
+    crate::only_accounts(&mut some, &[String::from("lists"), String::from("work")]).unwrap();
+    assert_eq!(names(&some), ["work", "lists"]);
+
+    let mut unknown = config();
+    let error = crate::only_accounts(
+        &mut unknown,
+        &[
+            String::from("wrok"),
+            String::from("home"),
+            String::from("x"),
+        ],
+    )
+    .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "no account named [wrok], [x] in the config"
+    );
+}
+
+/// Collects what a log layer writes.
+#[derive(Clone, Default)]
+struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn json_log_lines_carry_the_account_and_event_fields() {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let buffer = LogBuffer::default();
+    let writer = buffer.clone();
+    let subscriber =
+        tracing_subscriber::registry().with(crate::logging::json_layer(move || writer.clone()));
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info_span!("account", name = "work").in_scope(|| {
+            tracing::warn!(uid = 3, folder = "INBOX", "new mail");
+        });
+        tracing::info!("no account");
+    });
+
+    let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<serde_json::Value> = output
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["level"], "WARN");
+    assert_eq!(lines[0]["account"], "work");
+    assert_eq!(lines[0]["message"], "new mail");
+    assert_eq!(lines[0]["uid"], 3);
+    assert_eq!(lines[0]["folder"], "INBOX");
+    assert!(lines[0]["timestamp"].is_string());
+    assert!(lines[1].get("account").is_none());
+    assert_eq!(lines[1]["message"], "no account");
+}
+
+#[tokio::test]
+async fn new_session_logs_the_greeting_and_capabilities_at_debug() {
+    use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt};
+
+    let server = MockServer::start(Vec::new()).await;
+    server.advertise("MOVE");
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(account(&server), &notifier);
+    let buffer = LogBuffer::default();
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::registry()
+        .with(LevelFilter::DEBUG)
+        .with(crate::logging::json_layer(move || writer.clone()));
+    let _default = tracing::subscriber::set_default(subscriber);
+    unseen_mail.new_session().await.unwrap();
+
+    let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<serde_json::Value> = output
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let event = |message: &str| {
+        lines
+            .iter()
+            .find(|line| line["message"] == message)
+            .unwrap_or_else(|| panic!("no {:?} in {}", message, output))
+    };
+    assert_eq!(
+        event("server greeting")["greeting"],
+        "* OK [CAPABILITY IMAP4rev1 IDLE] mock ready"
+    );
+    let capabilities = event("server capabilities");
+    assert_eq!(capabilities["idle"], true);
+    assert_eq!(capabilities["move"], true);
+    assert_eq!(capabilities["condstore"], false);
+    assert!(capabilities["capabilities"]
+        .as_str()
+        .unwrap()
+        .contains("AUTH=PLAIN"));
+}
+
+#[tokio::test]
+async fn mail_is_notified_after_a_fetch_fails() {
+    let server = MockServer::start(vec![MockMessage::new(1, "a@example.com", "hi", "hi")]).await;
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(account(&server), &notifier);
+    // kept across reconnects like in `watch`
+    let mut mailbox = MailboxState::default();
+    server.drop_next_fetch();
+    let mut session = unseen_mail.new_session().await.unwrap();
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut mailbox)
+        .await
+        .unwrap_err();
+    assert_eq!(mailbox.last_notified, 0);
+    assert!(notifier.sent().is_empty());
+
+    let mut session = unseen_mail.new_session().await.unwrap();
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut mailbox)
+        .await
+        .unwrap();
+    let sent = notifier.sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].title, "@test has new mail in INBOX");
+    assert_eq!(mailbox.last_notified, 1);
+}
+
+#[tokio::test]
+async fn watchdog_is_only_fed_while_every_account_makes_progress() {
+    let path = std::env::temp_dir().join(format!("unseenmail-watchdog-{}", std::process::id()));
+    std::fs::remove_file(&path).ok();
+    let systemd = tokio::net::UnixDatagram::bind(&path).unwrap();
+    let liveness = Liveness::new(Duration::from_millis(200));
+    let sd_notify = Arc::new(SdNotify::new(path.to_str().unwrap()).unwrap());
+    task::spawn(systemd::watchdog(
+        sd_notify,
+        liveness.clone(),
+        Duration::from_millis(100),
+    ));
+    let server = MockServer::start(Vec::new()).await;
+    let unseen_mail = Arc::new(
+        unseen_mail(account(&server), &MemoryNotifier::default())
+            .report_liveness(Some(liveness.clone())),
+    );
+    let shutdown = CancellationToken::new();
+    task::spawn({
+        let shutdown = shutdown.clone();
+        async move { unseen_mail.run(shutdown).await }
+    });
+    let ping = || async {
+        let mut buf = [0; 64];
+        let len = tokio::time::timeout(Duration::from_secs(1), systemd.recv(&mut buf))
+            .await
+            .ok()?
+            .unwrap();
+        Some(buf[..len].to_vec())
+    };
+
+    // an account waiting in IDLE for minutes isn't stuck
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    assert!(server.commands().contains(&String::from("IDLE")));
+    assert!(liveness.stuck().is_empty());
+    assert_eq!(ping().await.unwrap(), b"WATCHDOG=1");
+
+    // an account that stops beating starves the watchdog
+    liveness.beat("wedged");
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert_eq!(liveness.stuck(), ["wedged"]);
+    let mut buf = [0; 64];
+    while systemd.try_recv(&mut buf).is_ok() {}
+    assert_eq!(ping().await, None);
+
+    liveness.forget("wedged");
+    assert_eq!(ping().await.unwrap(), b"WATCHDOG=1");
+    shutdown.cancel();
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn silent_clients_are_hung_up_on_and_cannot_hold_every_slot() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    task::spawn(crate::metrics::serve(
+        listener,
+        Arc::new(Metrics::default()),
+    ));
+    let mut silent = Vec::new();
+    for _ in 0..16 {
+        let stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+        silent.push(stream);
+    }
+
+    // answered once the silent clients are hung up on, not before
+    let started = std::time::Instant::now();
+    let response = http_exchange(port, b"GET /metrics HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    let waited = started.elapsed();
+    assert!(waited >= Duration::from_secs(9), "{:?}", waited);
+    assert!(waited < Duration::from_secs(15), "{:?}", waited);
+    for mut stream in silent {
+        let mut buf = Vec::new();
+        assert_eq!(stream.read_to_end(&mut buf).await.unwrap(), 0);
+    }
+}
+
+#[tokio::test]
+async fn mark_seen_flags_the_message_and_logs_out() {
+    use crate::actions::MarkSeen;
+
+    let server = MockServer::start(vec![MockMessage::new(1, "a@example.com", "hi", "hi")]).await;
+    let unseen_mail = unseen_mail(account(&server), &MemoryNotifier::default());
+    unseen_mail.mark_seen("INBOX", 1).await.unwrap();
+    assert!(server.flags(1).contains(&String::from("\\Seen")));
+    assert_eq!(server.commands().last().unwrap(), "LOGOUT");
+}
+
+#[tokio::test]
+async fn unread_count_searches_the_selected_folder() {
+    let server = MockServer::start(vec![MockMessage::new(1, "a@example.com", "hi", "hi")]).await;
+    server.deliver(MockMessage::new(2, "b@example.com", "hey", "hey"));
+    let notifier = MemoryNotifier::default();
+    let unseen_mail = unseen_mail(account_with(&server, "show_unread_count = true"), &notifier);
+    let mut session = unseen_mail.new_session().await.unwrap();
+    unseen_mail
+        .check_once(&mut session, "INBOX", &mut MailboxState::default())
+        .await
+        .unwrap();
+    let sent = notifier.sent();
+    assert_eq!(sent.len(), 2);
+    assert!(sent[0].title.ends_with(" (2 unread)"), "{}", sent[0].title);
+    let commands = server.commands();
+    assert!(!commands.iter().any(|command| command.starts_with("STATUS")));
+    assert!(commands.contains(&String::from("UID SEARCH UNSEEN")));
+}
+
+#[test]
+fn validate_checks_the_xoauth2_token_sources() {
+    let config = Config::from_toml(
+        r#"
+        [defaults]
+        server = "imap.example.com"
+        port = 993
+        username = "user"
+        ntfy_url = "https://ntfy.example.com"
+        ntfy_topic = "mail"
+
+        [[accounts]]
+        name = "none"
+        auth = { type = "xoauth2" }
+
+        [[accounts]]
+        name = "both"
+        auth = { type = "xoauth2", access_token = "token", access_token_command = "oauth2-token" }
+
+        [[accounts]]
+        name = "password"
+        password = "password"
+        password_command = "pass mail"
+        auth = { type = "xoauth2", access_token_command = "oauth2-token" }
+
+        [[accounts]]
+        name = "fine"
+        auth = { type = "xoauth2", access_token = "token" }
+        "#,
+    )
+    .unwrap();
+    let error = config.validate().unwrap_err().to_string();
+    assert_eq!(
+        error,
+        "invalid config:\n  \
+         - [none] xoauth2 needs exactly one of `access_token` or `access_token_command`\n  \
+         - [both] xoauth2 needs exactly one of `access_token` or `access_token_command`\n  \
+         - [password] `password`, `password_command` have no effect with `auth = { type = \"xoauth2\" }`"
+    );
+}
+
+#[tokio::test]
+async fn telegram_sends_escaped_html_and_silences_low_priority() {
+    use crate::notifier::{ChatId, Notifier, TelegramNotifier};
+
+    let (port, requests) = http_server(3, |request| {
+        if request.body.contains("blocked") {
+            (
+                "200 OK",
+                String::from(r#"{"ok":false,"description":"chat not found"}"#),
+            )
+        } else {
+            ("200 OK", String::from(r#"{"ok":true}"#))
+        }
+    })
+    .await;
+    let notifier = TelegramNotifier::with_api(
+        &format!("http://127.0.0.1:{}", port),
+        "123:abc",
+        &ChatId::Username(String::from("@mail")),
+    );
+    notifier
+        .notify(&Notification::new("<b>&", "a > b", Priority::Default))
+        .await
+        .unwrap();
+    notifier
+        .notify(&Notification::new("quiet", "list mail", Priority::Low))
+        .await
+        .unwrap();
+    let error = notifier
+        .notify(&Notification::new("blocked", "", Priority::High))
+        .await
+        .unwrap_err();
+    assert_eq!(error.to_string(), "telegram error: chat not found");
+
+    let requests = requests.await.unwrap();
+    assert_eq!(
+        requests[0].request_line,
+        "POST /bot123:abc/sendMessage HTTP/1.1"
+    );
+    let bodies: Vec<serde_json::Value> = requests
+        .iter()
+        .map(|request| serde_json::from_str(&request.body).unwrap())
+        .collect();
+    assert_eq!(bodies[0]["chat_id"], "@mail");
+    assert_eq!(bodies[0]["parse_mode"], "HTML");
+    assert_eq!(bodies[0]["text"], "<b>&lt;b&gt;&amp;</b>\na &gt; b");
+    assert_eq!(bodies[0]["disable_notification"], false);
+    assert_eq!(bodies[1]["disable_notification"], true);
+}
+
+#[tokio::test]
+async fn gotify_maps_priorities_onto_its_scale() {
+    use crate::notifier::{GotifyNotifier, Notifier};
+
+    let priorities = [
+        Priority::Min,
+        Priority::Low,
+        Priority::Default,
+        Priority::High,
+        Priority::Max,
+    ];
+    let (port, requests) = http_server(priorities.len(), |_| ("200 OK", String::from("{}"))).await;
+    let notifier = GotifyNotifier::new(&format!("http://127.0.0.1:{}/", port), "app-token");
+    for priority in priorities {
+        let click = (priority == Priority::Max).then(|| String::from("https://mail.example.com"));
+        notifier
+            .notify(&Notification::new("title", "message", priority).click(click))
+            .await
+            .unwrap();
+    }
+
+    let requests = requests.await.unwrap();
+    assert_eq!(
+        requests[0].request_line,
+        "POST /message?token=app-token HTTP/1.1"
+    );
+    let bodies: Vec<serde_json::Value> = requests
+        .iter()
+        .map(|request| serde_json::from_str(&request.body).unwrap())
+        .collect();
+    let levels: Vec<_> = bodies.iter().map(|body| body["priority"].clone()).collect();
+    assert_eq!(levels, [1, 3, 5, 8, 10]);
+    assert_eq!(bodies[0]["title"], "title");
+    assert_eq!(bodies[0]["message"], "message");
+    assert!(bodies[0].get("extras").is_none());
+    assert_eq!(
+        bodies[4]["extras"]["client::notification"]["click"]["url"],
+        "https://mail.example.com"
+    );
+}
+
+#[tokio::test]
+async fn discord_truncates_to_its_limits_by_characters() {
+    use crate::notifier::{DiscordNotifier, Notifier};
+
+    let (port, requests) = http_server(4, |_| ("204 No Content", String::new())).await;
+    let notifier = DiscordNotifier::new(&format!("http://127.0.0.1:{}/webhook", port));
+    // two bytes per character, so cutting by bytes would split one
+    for (length, click) in [
+        (256, None),
+        (257, Some("k9mail://messages")),
+        (2000, Some("https://mail.example.com")),
+        (2001, None),
+    ] {
+        let title = "é".repeat(length);
+        notifier
+            .notify(
+                &Notification::new(title, "message", Priority::High).click(click.map(String::from)),
+            )
+            .await
+            .unwrap();
+    }
+
+    let bodies: Vec<serde_json::Value> = requests
+        .await
+        .unwrap()
+        .iter()
+        .map(|request| serde_json::from_str(&request.body).unwrap())
+        .collect();
+    let text = |value: &serde_json::Value| value.as_str().unwrap().to_string();
+    assert_eq!(text(&bodies[0]["content"]), "é".repeat(256));
+    assert_eq!(text(&bodies[0]["embeds"][0]["title"]), "é".repeat(256));
+    assert_eq!(
+        text(&bodies[1]["embeds"][0]["title"]),
+        format!("{}…", "é".repeat(255))
+    );
+    assert_eq!(text(&bodies[2]["content"]), "é".repeat(2000));
+    assert_eq!(
+        text(&bodies[3]["content"]),
+        format!("{}…", "é".repeat(1999))
+    );
+    assert_eq!(bodies[0]["embeds"][0]["description"], "message");
+    assert_eq!(bodies[0]["embeds"][0]["color"], 0xed4245);
+    // Discord rejects links it can't open
+    assert!(bodies[1]["embeds"][0].get("url").is_none());
+    assert_eq!(bodies[2]["embeds"][0]["url"], "https://mail.example.com");
+}
+
+#[tokio::test]
+async fn matrix_sends_each_message_with_its_own_transaction_id() {
+    use crate::notifier::{MatrixNotifier, Notifier};
+
+    let (port, requests) = http_server(3, |request| {
+        if request.body.contains("forbidden") {
+            (
+                "403 Forbidden",
+                String::from(r#"{"errcode":"M_FORBIDDEN","error":"not in the room"}"#),
+            )
+        } else {
+            ("200 OK", String::from(r#"{"event_id":"$event"}"#))
+        }
+    })
+    .await;
+    let notifier = MatrixNotifier::new(
+        &format!("http://127.0.0.1:{}/", port),
+        "syt_token",
+        "!room:example.com",
+    );
+    notifier
+        .notify(&Notification::new(
+            "<new> mail",
+            "a & b\nc",
+            Priority::Default,
+        ))
+        .await
+        .unwrap();
+    notifier
+        .notify(&Notification::new("lists", "digest", Priority::Low))
+        .await
+        .unwrap();
+    let error = notifier
+        .notify(&Notification::new("forbidden", "", Priority::Default))
+        .await
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "matrix error 403 Forbidden: M_FORBIDDEN not in the room"
+    );
+
+    let requests = requests.await.unwrap();
+    let txn_ids: Vec<_> = requests
+        .iter()
+        .map(|request| {
+            let path = request.request_line.split(' ').nth(1).unwrap();
+            let (room, txn_id) = path.rsplit_once('/').unwrap();
+            assert_eq!(
+                room,
+                "/_matrix/client/v3/rooms/%21room%3Aexample%2Ecom/send/m.room.message"
+            );
+            txn_id.to_string()
+        })
+        .collect();
+    assert_ne!(txn_ids[0], txn_ids[1]);
+    assert_ne!(txn_ids[1], txn_ids[2]);
+    assert_eq!(requests[0].headers["authorization"], "Bearer syt_token");
+    let bodies: Vec<serde_json::Value> = requests
+        .iter()
+        .map(|request| serde_json::from_str(&request.body).unwrap())
+        .collect();
+    assert_eq!(bodies[0]["msgtype"], "m.text");
+    assert_eq!(bodies[0]["body"], "<new> mail\na & b\nc");
+    assert_eq!(
+        bodies[0]["formatted_body"],
+        "<b>&lt;new&gt; mail</b><br>a &amp; b<br>c"
+    );
+    assert_eq!(bodies[1]["msgtype"], "m.notice");
+}